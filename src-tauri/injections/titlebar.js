@@ -253,6 +253,11 @@
     contentProtection: "menu.content_protection",
     autostart: "menu.autostart",
     startMinimized: "menu.start_minimized",
+    linePin: "menu.line.pin",
+    lineInstallCrx: "menu.line.install_crx",
+    userExtAddFolder: "menu.user_ext.add_folder",
+    userExtAddCrx: "menu.user_ext.add_crx",
+    userExtAddStore: "menu.user_ext.add_store",
     resetProfile: "menu.reset_profile",
     logError: "menu.log.error",
     logWarn: "menu.log.warn",
@@ -281,6 +286,28 @@
     }
   };
 
+  const getLineVersions = async () => {
+    const invoke = getTauriInvoke();
+    if (!invoke) return [];
+    try {
+      return await invoke("list_line_extension_versions");
+    } catch (error) {
+      console.warn("[menu] list_line_extension_versions failed", error);
+      return [];
+    }
+  };
+
+  const getUserExtensions = async () => {
+    const invoke = getTauriInvoke();
+    if (!invoke) return [];
+    try {
+      return await invoke("get_user_extensions");
+    } catch (error) {
+      console.warn("[menu] get_user_extensions failed", error);
+      return [];
+    }
+  };
+
   const baseMenuItems = [
     {
       id: MENU_IDS.contentProtection,
@@ -297,9 +324,20 @@
       id: MENU_IDS.startMinimized,
       label: "起動時に最小化",
       type: "check"
+    },
+    {
+      id: MENU_IDS.linePin,
+      label: "LINE 拡張機能のバージョンを固定",
+      type: "check"
     }
   ];
 
+  const installCrxItem = {
+    id: MENU_IDS.lineInstallCrx,
+    label: "CRX ファイルから LINE 拡張機能をインストール",
+    type: "action"
+  };
+
   const resetProfileItem = {
     id: MENU_IDS.resetProfile,
     label: "プロファイルデータをリセット",
@@ -339,8 +377,67 @@
     }
   ];
 
-  const buildMenuModel = (isDev) => {
-    const items = [...baseMenuItems, { type: "separator" }, resetProfileItem];
+  const buildRollbackItems = (versions) =>
+    versions
+      .filter((entry) => !entry.active)
+      .map((entry) => ({
+        id: `line.rollback.${entry.version}`,
+        label: `LINE 拡張機能を v${entry.version} に戻す`,
+        type: "action",
+        rollbackVersion: entry.version
+      }));
+
+  const userExtensionAddItems = [
+    {
+      id: MENU_IDS.userExtAddFolder,
+      label: "フォルダから拡張機能を追加",
+      type: "action"
+    },
+    {
+      id: MENU_IDS.userExtAddCrx,
+      label: "CRX ファイルから拡張機能を追加",
+      type: "action"
+    },
+    {
+      id: MENU_IDS.userExtAddStore,
+      label: "Web ストアから拡張機能を追加",
+      type: "action"
+    }
+  ];
+
+  const buildUserExtensionItems = (extensions) =>
+    extensions.flatMap((extension) => {
+      const label = extension.error
+        ? `${extension.name} (読み込めません)`
+        : `${extension.name} v${extension.version}`;
+      return [
+        {
+          id: `user-ext.toggle.${extension.id}`,
+          label,
+          type: "check",
+          checked: extension.enabled,
+          userExtension: extension
+        },
+        {
+          id: `user-ext.remove.${extension.id}`,
+          label: `「${extension.name}」を削除`,
+          type: "action",
+          removeUserExtension: extension
+        }
+      ];
+    });
+
+  const buildMenuModel = (isDev, versions = [], userExtensions = []) => {
+    const items = [...baseMenuItems, { type: "separator" }, installCrxItem, resetProfileItem];
+    const rollbackItems = buildRollbackItems(versions);
+    if (rollbackItems.length > 0) {
+      items.push({ type: "separator" }, ...rollbackItems);
+    }
+    items.push(
+      { type: "separator" },
+      ...buildUserExtensionItems(userExtensions),
+      ...userExtensionAddItems
+    );
     if (isDev) {
       items.push({ type: "separator" }, ...logMenuItems);
     }
@@ -375,6 +472,9 @@
     if (item.type === "radio") {
       button.classList.add("is-radio");
     }
+    if (item.checked) {
+      button.classList.add("is-checked");
+    }
 
     let check = null;
     if (item.type !== "action") {
@@ -417,6 +517,63 @@
           }
           return;
         }
+        if (item.id === MENU_IDS.userExtAddStore) {
+          const invoke = getTauriInvoke();
+          if (!invoke) return;
+          const input = window.prompt("拡張機能の ID または Web ストアの URL を入力してください");
+          if (!input || !input.trim()) return;
+          try {
+            await invoke("install_store_user_extension", { id: input.trim() });
+          } catch (error) {
+            console.warn("[menu] install store extension failed", error);
+            window.alert(`拡張機能をインストールできませんでした。\n${error}`);
+          }
+          await syncMenu();
+          return;
+        }
+        if (item.removeUserExtension) {
+          const invoke = getTauriInvoke();
+          if (!invoke) return;
+          try {
+            const confirmed = await invoke("confirm_uninstall_user_extension", {
+              name: item.removeUserExtension.name
+            });
+            if (!confirmed) return;
+            await invoke("uninstall_user_extension", { id: item.removeUserExtension.id });
+          } catch (error) {
+            console.warn("[menu] remove user extension failed", error);
+          }
+          await syncMenu();
+          return;
+        }
+        if (
+          item.id === MENU_IDS.lineInstallCrx ||
+          item.id === MENU_IDS.userExtAddFolder ||
+          item.id === MENU_IDS.userExtAddCrx
+        ) {
+          const invoke = getTauriInvoke();
+          if (!invoke) return;
+          try {
+            await invoke("menu_action", { id: item.id });
+          } catch (error) {
+            console.warn("[menu] install crx failed", error);
+          }
+          return;
+        }
+        if (item.rollbackVersion) {
+          const invoke = getTauriInvoke();
+          if (!invoke) return;
+          try {
+            const confirmed = await invoke("confirm_rollback_line_extension", {
+              version: item.rollbackVersion
+            });
+            if (!confirmed) return;
+            await invoke("rollback_line_extension", { version: item.rollbackVersion });
+          } catch (error) {
+            console.warn("[menu] rollback failed", error);
+          }
+          return;
+        }
         if (item.id === "window.close") {
           const currentWindow = getCurrentWindow();
           if (currentWindow) {
@@ -429,6 +586,19 @@
       const invoke = getTauriInvoke();
       if (!invoke) return;
 
+      if (item.userExtension) {
+        try {
+          await invoke("set_user_extension_enabled", {
+            id: item.userExtension.id,
+            enabled: !item.userExtension.enabled
+          });
+        } catch (error) {
+          console.warn("[menu] toggle user extension failed", error);
+        }
+        await syncMenu();
+        return;
+      }
+
       try {
         await invoke("menu_action", { id: item.id });
       } catch (error) {
@@ -482,6 +652,7 @@
       setMenuItemChecked(MENU_IDS.contentProtection, !!protectedState);
       setMenuItemChecked(MENU_IDS.autostart, !!settings?.autoStart);
       setMenuItemChecked(MENU_IDS.startMinimized, !!settings?.startMinimized);
+      setMenuItemChecked(MENU_IDS.linePin, !!settings?.pinnedLineVersion);
       setLogLevelChecked(settings?.logLevel || "info");
     } catch (error) {
       console.warn("[menu] refresh failed", error);
@@ -525,10 +696,13 @@
     });
   };
 
-  const syncMenuForDev = async () => {
-    const isDev = await getIsDev();
-    if (!isDev) return;
-    renderMenu(buildMenuModel(true));
+  const syncMenu = async () => {
+    const [isDev, versions, userExtensions] = await Promise.all([
+      getIsDev(),
+      getLineVersions(),
+      getUserExtensions()
+    ]);
+    renderMenu(buildMenuModel(isDev, versions, userExtensions));
     await refreshMenuState();
   };
 
@@ -660,7 +834,7 @@
 
   refreshMenuState();
   maybeListenContentProtection();
-  syncMenuForDev();
+  syncMenu();
   refreshMaximizeState();
   bindWindowStateSync();
 
@@ -1,5 +1,8 @@
+use crate::commands::{pick_line_extension_crx, pick_user_extension};
 use crate::content_protection::{is_content_protected, set_content_protection_from_app};
+use crate::extensions::{line_dir, read_manifest_version};
 use crate::logger::{apply_log_level, LogLevel};
+use crate::paths::extensions_root;
 use crate::settings::{load_settings, save_settings};
 use crate::tray::set_tray_enabled;
 use log::{info, warn};
@@ -10,6 +13,10 @@ use tauri_plugin_autostart::ManagerExt;
 const MENU_CONTENT_PROTECTION_ID: &str = "menu.content_protection";
 const MENU_AUTOSTART_ID: &str = "menu.autostart";
 const MENU_START_MINIMIZED_ID: &str = "menu.start_minimized";
+const MENU_LINE_PIN_ID: &str = "menu.line.pin";
+const MENU_LINE_INSTALL_CRX_ID: &str = "menu.line.install_crx";
+const MENU_USER_EXT_ADD_FOLDER_ID: &str = "menu.user_ext.add_folder";
+const MENU_USER_EXT_ADD_CRX_ID: &str = "menu.user_ext.add_crx";
 const MENU_LOG_ERROR_ID: &str = "menu.log.error";
 const MENU_LOG_WARN_ID: &str = "menu.log.warn";
 const MENU_LOG_INFO_ID: &str = "menu.log.info";
@@ -20,6 +27,7 @@ pub(crate) struct MenuState {
   content_protection: CheckMenuItem<Wry>,
   autostart: CheckMenuItem<Wry>,
   start_minimized: CheckMenuItem<Wry>,
+  line_pin: CheckMenuItem<Wry>,
   log_error: CheckMenuItem<Wry>,
   log_warn: CheckMenuItem<Wry>,
   log_info: CheckMenuItem<Wry>,
@@ -61,6 +69,14 @@ pub(crate) fn build_menu(
     settings.start_minimized,
     None::<&str>,
   )?;
+  let line_pin = CheckMenuItem::with_id(
+    app_handle,
+    MenuId::new(MENU_LINE_PIN_ID),
+    "LINE 拡張機能のバージョンを固定",
+    true,
+    settings.pinned_line_version.is_some(),
+    None::<&str>,
+  )?;
   let log_error = CheckMenuItem::with_id(
     app_handle,
     MenuId::new(MENU_LOG_ERROR_ID),
@@ -121,6 +137,7 @@ pub(crate) fn build_menu(
     &content_protection,
     &autostart,
     &start_minimized,
+    &line_pin,
     &settings_separator,
   ];
   if is_dev() {
@@ -134,6 +151,7 @@ pub(crate) fn build_menu(
     content_protection,
     autostart,
     start_minimized,
+    line_pin,
     log_error,
     log_warn,
     log_info,
@@ -208,6 +226,19 @@ pub(crate) fn handle_menu_action_id(app_handle: &tauri::AppHandle, id: &str) {
       }
       set_menu_checked(app_handle, MENU_START_MINIMIZED_ID, tray_enabled);
     }
+    id if id == MENU_LINE_PIN_ID => {
+      let pinned = toggle_line_pin(app_handle);
+      set_menu_checked(app_handle, MENU_LINE_PIN_ID, pinned);
+    }
+    id if id == MENU_LINE_INSTALL_CRX_ID => {
+      pick_line_extension_crx(app_handle);
+    }
+    id if id == MENU_USER_EXT_ADD_FOLDER_ID => {
+      pick_user_extension(app_handle, false);
+    }
+    id if id == MENU_USER_EXT_ADD_CRX_ID => {
+      pick_user_extension(app_handle, true);
+    }
     id if id == MENU_LOG_ERROR_ID => {
       update_log_level(app_handle, LogLevel::Error);
     }
@@ -241,6 +272,9 @@ pub(crate) fn set_menu_checked(app_handle: &tauri::AppHandle, id: &str, checked:
     MENU_START_MINIMIZED_ID => {
       let _ = state.start_minimized.set_checked(checked);
     }
+    MENU_LINE_PIN_ID => {
+      let _ = state.line_pin.set_checked(checked);
+    }
     MENU_LOG_ERROR_ID => {
       let _ = state.log_error.set_checked(checked);
     }
@@ -260,6 +294,31 @@ pub(crate) fn set_menu_checked(app_handle: &tauri::AppHandle, id: &str, checked:
   }
 }
 
+fn toggle_line_pin(app_handle: &tauri::AppHandle) -> bool {
+  let Ok(mut settings) = load_settings(app_handle) else {
+    return false;
+  };
+  settings.pinned_line_version = if settings.pinned_line_version.is_some() {
+    None
+  } else {
+    let active = extensions_root(app_handle)
+      .ok()
+      .and_then(|root| read_manifest_version(&line_dir(&root)));
+    if active.is_none() {
+      warn!("[versions] pin failed: no active extension");
+    }
+    active
+  };
+  if let Err(error) = save_settings(app_handle, &settings) {
+    warn!("[versions] pin save failed: {error:#}");
+  }
+  info!(
+    "[versions] pinned={}",
+    settings.pinned_line_version.as_deref().unwrap_or("-")
+  );
+  settings.pinned_line_version.is_some()
+}
+
 fn update_log_level(app_handle: &tauri::AppHandle, level: LogLevel) {
   apply_log_level(level);
   if let Ok(mut settings) = load_settings(app_handle) {
@@ -0,0 +1,184 @@
+use crate::crx::{
+  extension_id_from_public_key, pack_crx_dir, parse_crx, AcceptedCrx, CrxSigningKey,
+};
+use anyhow::{anyhow, Result};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PACK_CRX_USAGE: &str =
+  "usage: refined-line pack-crx <dir> --key <key.pem> [--out <file.crx>]";
+
+#[derive(Debug, Default)]
+pub(crate) struct CliOptions {
+  pub(crate) install_crx: Option<PathBuf>,
+}
+
+pub(crate) fn parse_cli_args<I>(args: I) -> CliOptions
+where
+  I: IntoIterator<Item = OsString>,
+{
+  let mut options = CliOptions::default();
+  let mut args = args.into_iter();
+  while let Some(arg) = args.next() {
+    let Some(text) = arg.to_str() else {
+      continue;
+    };
+    if text == "--install-crx" {
+      options.install_crx = args.next().map(PathBuf::from);
+    } else if let Some(path) = text.strip_prefix("--install-crx=") {
+      options.install_crx = Some(PathBuf::from(path));
+    }
+  }
+  options
+}
+
+#[derive(Debug)]
+struct PackCrxArgs {
+  dir: PathBuf,
+  key: PathBuf,
+  out: Option<PathBuf>,
+}
+
+/// Runs a subcommand such as `pack-crx` instead of starting the app.
+/// Returns the exit code, or `None` when no subcommand was given.
+pub(crate) fn run_subcommand<I>(args: I) -> Option<i32>
+where
+  I: IntoIterator<Item = OsString>,
+{
+  let mut args = args.into_iter();
+  let first = args.next()?;
+  if first.to_str() != Some("pack-crx") {
+    return None;
+  }
+  attach_parent_console();
+  let parsed = match parse_pack_crx_args(args) {
+    Ok(parsed) => parsed,
+    Err(error) => {
+      eprintln!("{error}\n{PACK_CRX_USAGE}");
+      return Some(2);
+    }
+  };
+  match pack_crx(&parsed) {
+    Ok(()) => Some(0),
+    Err(error) => {
+      eprintln!("pack-crx failed: {error:#}");
+      Some(1)
+    }
+  }
+}
+
+/// Release builds use the Windows GUI subsystem and start without a console,
+/// so output would be lost. Writes go to the console of the launching shell
+/// instead, if there is one.
+#[cfg(windows)]
+fn attach_parent_console() {
+  use windows::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+  // Fails when a console is already attached (debug builds) or there is none.
+  let _ = unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
+}
+
+#[cfg(not(windows))]
+fn attach_parent_console() {}
+
+fn parse_pack_crx_args(args: impl Iterator<Item = OsString>) -> Result<PackCrxArgs> {
+  let mut dir = None;
+  let mut key = None;
+  let mut out = None;
+  let mut args = args.map(PathBuf::from);
+  while let Some(arg) = args.next() {
+    match arg.to_str() {
+      Some("--key") => key = args.next(),
+      Some("--out") => out = args.next(),
+      Some(text) if text.starts_with("--") => return Err(anyhow!("unknown option {text}")),
+      _ if dir.is_none() => dir = Some(arg),
+      _ => return Err(anyhow!("unexpected argument {}", arg.display())),
+    }
+  }
+  Ok(PackCrxArgs {
+    dir: dir.ok_or_else(|| anyhow!("missing extension directory"))?,
+    key: key.ok_or_else(|| anyhow!("missing --key"))?,
+    out,
+  })
+}
+
+fn pack_crx(args: &PackCrxArgs) -> Result<()> {
+  let key_bytes =
+    fs::read(&args.key).map_err(|error| anyhow!("read {} failed: {error}", args.key.display()))?;
+  let key = CrxSigningKey::from_bytes(&key_bytes)?;
+  let crx = pack_crx_dir(&args.dir, &key)?;
+  // Read it back the way an install would before handing it out.
+  let parsed = parse_crx(&crx, AcceptedCrx::Crx3)?;
+  let out = match &args.out {
+    Some(out) => out.clone(),
+    None => default_out_path(&args.dir)?,
+  };
+  fs::write(&out, &crx).map_err(|error| anyhow!("write {} failed: {error}", out.display()))?;
+  println!(
+    "{} {}",
+    extension_id_from_public_key(&parsed.public_key),
+    out.display()
+  );
+  Ok(())
+}
+
+/// `<name>.crx` next to the extension folder, named after the folder itself
+/// so that `.` or `ext/..` still give a usable file name.
+fn default_out_path(dir: &Path) -> Result<PathBuf> {
+  let dir =
+    fs::canonicalize(dir).map_err(|error| anyhow!("read {} failed: {error}", dir.display()))?;
+  let (Some(parent), Some(name)) = (dir.parent(), dir.file_name()) else {
+    return Err(anyhow!(
+      "cannot name the crx after {}; pass --out",
+      dir.display()
+    ));
+  };
+  let mut file_name = name.to_os_string();
+  file_name.push(".crx");
+  Ok(parent.join(file_name))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use p256::pkcs8::EncodePrivateKey;
+
+  #[test]
+  fn names_the_default_crx_after_the_folder() {
+    let root = tempfile::tempdir().unwrap();
+    let root_path = fs::canonicalize(root.path()).unwrap();
+    let dir = root_path.join("my.ext");
+    fs::create_dir_all(dir.join("js")).unwrap();
+    let expected = root_path.join("my.ext.crx");
+    assert_eq!(default_out_path(&dir).unwrap(), expected);
+    assert_eq!(default_out_path(&dir.join(".")).unwrap(), expected);
+    assert_eq!(
+      default_out_path(&dir.join("js").join("..")).unwrap(),
+      expected
+    );
+  }
+
+  #[test]
+  fn packs_next_to_the_folder_by_default() {
+    let root = tempfile::tempdir().unwrap();
+    let dir = root.path().join("helper");
+    fs::create_dir(&dir).unwrap();
+    fs::write(
+      dir.join("manifest.json"),
+      r#"{"name":"helper","version":"1.0","manifest_version":3}"#,
+    )
+    .unwrap();
+    let key = p256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+    let key_path = root.path().join("key.der");
+    fs::write(&key_path, key.to_pkcs8_der().unwrap().as_bytes()).unwrap();
+
+    pack_crx(&PackCrxArgs {
+      dir: dir.join("."),
+      key: key_path,
+      out: None,
+    })
+    .unwrap();
+    let crx = fs::read(root.path().join("helper.crx")).unwrap();
+    parse_crx(&crx, AcceptedCrx::Crx3).unwrap();
+  }
+}
@@ -1,7 +1,18 @@
-use crate::paths::profile_reset_marker;
+use crate::config::load_config;
+use crate::extensions::{line_dir, queue_local_crx, read_install_record, InstallRecord};
+use crate::http_client::apply_network_settings;
+use crate::line_versions::{list_line_versions, LineVersion};
+use crate::paths::{extensions_root, profile_reset_marker};
+use crate::progress::{ExtensionProgress, ProgressReporter, ProgressState, RetryAction};
 use crate::settings::{load_settings, save_settings, AppSettings};
-use log::info;
-use tauri::Window;
+use crate::user_extensions::{
+  add_user_extension, install_store_extension, list_user_extensions, parse_store_extension_id,
+  remove_user_extension, user_extension_exists, user_extensions_dir, UserExtension,
+};
+use log::{info, warn};
+use std::path::Path;
+use std::sync::Arc;
+use tauri::{Manager, Window};
 use tauri_plugin_dialog::{DialogExt, MessageDialogButtons};
 
 #[tauri::command]
@@ -15,9 +26,33 @@ pub(crate) fn update_settings(
   settings: AppSettings,
 ) -> Result<AppSettings, String> {
   save_settings(&app_handle, &settings).map_err(|error| error.to_string())?;
+  // Saved first, so a bad rootCaFile does not block unrelated settings.
+  apply_network_settings(&settings.network).map_err(|error| format!("{error:#}"))?;
   Ok(settings)
 }
 
+#[tauri::command]
+pub(crate) fn get_line_extension_info(
+  app_handle: tauri::AppHandle,
+) -> Result<Option<InstallRecord>, String> {
+  let root = extensions_root(&app_handle).map_err(|error| error.to_string())?;
+  Ok(read_install_record(&root))
+}
+
+#[tauri::command]
+pub(crate) fn get_extension_progress(app_handle: tauri::AppHandle) -> Option<ExtensionProgress> {
+  app_handle
+    .try_state::<Arc<ProgressState>>()
+    .and_then(|state| state.last())
+}
+
+#[tauri::command]
+pub(crate) fn respond_extension_retry(app_handle: tauri::AppHandle, action: RetryAction) {
+  if let Some(state) = app_handle.try_state::<Arc<ProgressState>>() {
+    state.request(action);
+  }
+}
+
 #[tauri::command]
 pub(crate) fn get_is_dev() -> bool {
   tauri::is_dev()
@@ -57,3 +92,265 @@ pub(crate) async fn confirm_reset_profile(app_handle: tauri::AppHandle) -> Resul
     .await
     .ok_or_else(|| "dialog cancelled".to_string())
 }
+
+#[tauri::command]
+pub(crate) fn list_line_extension_versions(
+  app_handle: tauri::AppHandle,
+) -> Result<Vec<LineVersion>, String> {
+  let root = extensions_root(&app_handle).map_err(|error| error.to_string())?;
+  Ok(list_line_versions(&root, &line_dir(&root)))
+}
+
+#[tauri::command]
+pub(crate) async fn confirm_rollback_line_extension(
+  app_handle: tauri::AppHandle,
+  version: String,
+) -> Result<bool, String> {
+  let (tx, mut rx) = tauri::async_runtime::channel(1);
+  app_handle
+    .dialog()
+    .message(format!(
+      "LINE 拡張機能を v{version} に戻して再起動します。戻したバージョンは固定され、自動更新されなくなります。続行しますか？"
+    ))
+    .title("拡張機能のバージョンを戻す")
+    .buttons(MessageDialogButtons::YesNo)
+    .show(move |confirmed| {
+      let _ = tx.try_send(confirmed);
+    });
+  rx.recv()
+    .await
+    .ok_or_else(|| "dialog cancelled".to_string())
+}
+
+#[tauri::command]
+pub(crate) fn rollback_line_extension(
+  app_handle: tauri::AppHandle,
+  version: String,
+) -> Result<(), String> {
+  let root = extensions_root(&app_handle).map_err(|error| error.to_string())?;
+  let known = list_line_versions(&root, &line_dir(&root))
+    .iter()
+    .any(|entry| entry.version == version);
+  if !known {
+    return Err(format!("extension v{version} not found"));
+  }
+  // The swap itself happens in prepare_extensions on the next launch, while
+  // the extension files are not held open by WebView2.
+  let mut settings = load_settings(&app_handle).map_err(|error| error.to_string())?;
+  settings.pinned_line_version = Some(version.clone());
+  save_settings(&app_handle, &settings).map_err(|error| error.to_string())?;
+  info!("[versions] rollback to v{version} requested");
+  app_handle.restart();
+}
+
+#[tauri::command]
+pub(crate) fn install_line_extension_crx(
+  app_handle: tauri::AppHandle,
+  path: String,
+) -> Result<(), String> {
+  queue_local_crx(&app_handle, Path::new(&path)).map_err(|error| error.to_string())?;
+  prompt_restart_for_local_crx(&app_handle);
+  Ok(())
+}
+
+pub(crate) fn pick_line_extension_crx(app_handle: &tauri::AppHandle) {
+  let app_handle = app_handle.clone();
+  app_handle
+    .dialog()
+    .file()
+    .add_filter("Chrome 拡張機能", &["crx"])
+    .pick_file({
+      let app_handle = app_handle.clone();
+      move |path| {
+        let Some(path) = path else {
+          return;
+        };
+        let path = match path.into_path() {
+          Ok(path) => path,
+          Err(error) => {
+            warn!("[update] local crx path failed: {error:#}");
+            return;
+          }
+        };
+        if let Err(error) = queue_local_crx(&app_handle, &path) {
+          warn!("[update] local crx rejected: {error:#}");
+          app_handle
+            .dialog()
+            .message(format!("CRX ファイルを読み込めませんでした。\n{error}"))
+            .title("インストール失敗")
+            .show(|_| {});
+          return;
+        }
+        prompt_restart_for_local_crx(&app_handle);
+      }
+    });
+}
+
+fn prompt_restart_for_local_crx(app_handle: &tauri::AppHandle) {
+  let app_handle = app_handle.clone();
+  app_handle
+    .dialog()
+    .message("CRX ファイルを読み込みました。再起動して適用しますか？")
+    .title("拡張機能のインストール")
+    .buttons(MessageDialogButtons::YesNo)
+    .show({
+      let app_handle = app_handle.clone();
+      move |confirmed| {
+        if confirmed {
+          app_handle.restart();
+        }
+      }
+    });
+}
+
+#[tauri::command]
+pub(crate) fn get_user_extensions(
+  app_handle: tauri::AppHandle,
+) -> Result<Vec<UserExtension>, String> {
+  let root = extensions_root(&app_handle).map_err(|error| error.to_string())?;
+  let settings = load_settings(&app_handle).unwrap_or_default();
+  Ok(list_user_extensions(
+    &root,
+    &settings.disabled_user_extensions,
+  ))
+}
+
+/// Installs an extension from the Web Store by ID or store URL.
+#[tauri::command]
+pub(crate) async fn install_store_user_extension(
+  app_handle: tauri::AppHandle,
+  id: String,
+) -> Result<UserExtension, String> {
+  let task_handle = app_handle.clone();
+  let extension = tauri::async_runtime::spawn_blocking(move || {
+    let id = parse_store_extension_id(&id)?;
+    let config = load_config(&task_handle)?;
+    let root = extensions_root(&task_handle)?;
+    install_store_extension(&config, &root, &id, &ProgressReporter::new(&task_handle))
+  })
+  .await
+  .map_err(|error| error.to_string())?
+  .map_err(|error| format!("{error:#}"))?;
+  prompt_restart_for_user_extensions(&app_handle);
+  Ok(extension)
+}
+
+#[tauri::command]
+pub(crate) async fn confirm_uninstall_user_extension(
+  app_handle: tauri::AppHandle,
+  name: String,
+) -> Result<bool, String> {
+  let (tx, mut rx) = tauri::async_runtime::channel(1);
+  app_handle
+    .dialog()
+    .message(format!("拡張機能「{name}」を削除しますか？"))
+    .title("拡張機能の削除")
+    .buttons(MessageDialogButtons::YesNo)
+    .show(move |confirmed| {
+      let _ = tx.try_send(confirmed);
+    });
+  rx.recv()
+    .await
+    .ok_or_else(|| "dialog cancelled".to_string())
+}
+
+#[tauri::command]
+pub(crate) fn uninstall_user_extension(
+  app_handle: tauri::AppHandle,
+  id: String,
+) -> Result<(), String> {
+  let root = extensions_root(&app_handle).map_err(|error| error.to_string())?;
+  remove_user_extension(&root, &id).map_err(|error| error.to_string())?;
+  let mut settings = load_settings(&app_handle).map_err(|error| error.to_string())?;
+  if settings.disabled_user_extensions.contains(&id) {
+    settings
+      .disabled_user_extensions
+      .retain(|entry| entry != &id);
+    save_settings(&app_handle, &settings).map_err(|error| error.to_string())?;
+  }
+  prompt_restart_for_user_extensions(&app_handle);
+  Ok(())
+}
+
+#[tauri::command]
+pub(crate) fn set_user_extension_enabled(
+  app_handle: tauri::AppHandle,
+  id: String,
+  enabled: bool,
+) -> Result<(), String> {
+  let root = extensions_root(&app_handle).map_err(|error| error.to_string())?;
+  if !user_extension_exists(&user_extensions_dir(&root), &id) {
+    return Err(format!("user extension {id} not found"));
+  }
+  let mut settings = load_settings(&app_handle).map_err(|error| error.to_string())?;
+  settings
+    .disabled_user_extensions
+    .retain(|entry| entry != &id);
+  if !enabled {
+    settings.disabled_user_extensions.push(id.clone());
+  }
+  save_settings(&app_handle, &settings).map_err(|error| error.to_string())?;
+  info!("[ext] user extension {id} enabled={enabled}");
+  prompt_restart_for_user_extensions(&app_handle);
+  Ok(())
+}
+
+/// Lets the user pick an unpacked extension folder, or a `.crx` file when
+/// `crx` is set, and copies it into `extensions/user`.
+pub(crate) fn pick_user_extension(app_handle: &tauri::AppHandle, crx: bool) {
+  let app_handle = app_handle.clone();
+  let on_pick = {
+    let app_handle = app_handle.clone();
+    move |path: Option<tauri_plugin_dialog::FilePath>| {
+      let Some(path) = path else {
+        return;
+      };
+      let path = match path.into_path() {
+        Ok(path) => path,
+        Err(error) => {
+          warn!("[ext] user extension path failed: {error:#}");
+          return;
+        }
+      };
+      let result = extensions_root(&app_handle).and_then(|root| {
+        let config = load_config(&app_handle)?;
+        add_user_extension(&user_extensions_dir(&root), &path, &config.extract_limits)
+      });
+      if let Err(error) = result {
+        warn!("[ext] user extension rejected: {error:#}");
+        app_handle
+          .dialog()
+          .message(format!("拡張機能を読み込めませんでした。\n{error}"))
+          .title("インストール失敗")
+          .show(|_| {});
+        return;
+      }
+      prompt_restart_for_user_extensions(&app_handle);
+    }
+  };
+  let picker = app_handle.dialog().file();
+  if crx {
+    picker
+      .add_filter("Chrome 拡張機能", &["crx"])
+      .pick_file(on_pick);
+  } else {
+    picker.pick_folder(on_pick);
+  }
+}
+
+fn prompt_restart_for_user_extensions(app_handle: &tauri::AppHandle) {
+  let app_handle = app_handle.clone();
+  app_handle
+    .dialog()
+    .message("拡張機能の変更は再起動後に反映されます。今すぐ再起動しますか？")
+    .title("拡張機能")
+    .buttons(MessageDialogButtons::YesNo)
+    .show({
+      let app_handle = app_handle.clone();
+      move |confirmed| {
+        if confirmed {
+          app_handle.restart();
+        }
+      }
+    });
+}
@@ -1,5 +1,8 @@
+use crate::crx::ExtractLimits;
+use crate::update_sources::UpdateSource;
 use anyhow::{anyhow, Result};
 use std::fs;
+use std::path::{Path, PathBuf};
 use tauri::path::BaseDirectory;
 use tauri::Manager;
 
@@ -11,6 +14,26 @@ pub(crate) struct AppConfig {
   pub(crate) line_entry_path: String,
   #[serde(rename = "update2BaseUrl")]
   pub(crate) update2_base_url: String,
+  /// Ordered update sources. When empty, only `update2BaseUrl` is used.
+  #[serde(rename = "updateSources", default)]
+  pub(crate) update_sources: Vec<UpdateSource>,
+  /// Bundled CRX file or directory used when nothing is installed and the
+  /// update server is unreachable. Relative paths resolve against resources.
+  #[serde(rename = "seedCrx", default)]
+  pub(crate) seed_crx: Option<String>,
+  #[serde(rename = "extractLimits", default)]
+  pub(crate) extract_limits: ExtractLimits,
+}
+
+impl AppConfig {
+  pub(crate) fn update_sources(&self) -> Vec<UpdateSource> {
+    if self.update_sources.is_empty() {
+      return vec![UpdateSource::Update2 {
+        url: self.update2_base_url.clone(),
+      }];
+    }
+    self.update_sources.clone()
+  }
 }
 
 pub(crate) fn load_config(app: &tauri::AppHandle) -> Result<AppConfig> {
@@ -22,3 +45,20 @@ pub(crate) fn load_config(app: &tauri::AppHandle) -> Result<AppConfig> {
   let config: AppConfig = serde_json::from_str(&raw)?;
   Ok(config)
 }
+
+pub(crate) fn resolve_seed_crx(
+  app: &tauri::AppHandle,
+  config: &AppConfig,
+) -> Result<Option<PathBuf>> {
+  let Some(seed) = config.seed_crx.as_deref() else {
+    return Ok(None);
+  };
+  if Path::new(seed).is_absolute() {
+    return Ok(Some(PathBuf::from(seed)));
+  }
+  let path = app
+    .path()
+    .resolve(seed, BaseDirectory::Resource)
+    .map_err(|error| anyhow!("seed path error: {error}"))?;
+  Ok(Some(path))
+}
@@ -1,15 +1,30 @@
+use crate::http_client::http_agent;
+use crate::manifest::ExtensionManifest;
 use anyhow::{anyhow, Result};
 use base64::engine::general_purpose::STANDARD as base64_standard;
 use base64::Engine;
+use log::info;
+use p256::ecdsa::{
+  Signature as EcdsaSignature, SigningKey as EcdsaSigningKey, VerifyingKey as EcdsaVerifyingKey,
+};
 use prost::Message;
-use serde_json::Value;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs1v15::{
+  Signature as RsaSignature, SigningKey as RsaSigningKey, VerifyingKey as RsaVerifyingKey,
+};
+use rsa::pkcs8::{AssociatedOid, DecodePrivateKey, DecodePublicKey, EncodePublicKey};
+use rsa::signature::{DigestSigner, DigestVerifier, SignatureEncoding};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
 use sha2::{Digest, Sha256};
-use std::fs;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
 use std::io::{Cursor, Read, Write};
-use std::path::Path;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
 use url::Url;
-use zip::ZipArchive;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
 
 #[derive(Clone, PartialEq, Message)]
 struct AsymmetricKeyProof {
@@ -35,75 +50,142 @@ struct SignedData {
   crx_id: Option<Vec<u8>>,
 }
 
-pub(crate) struct ParsedCrx {
-  pub(crate) public_key: Vec<u8>,
-  pub(crate) zip_bytes: Vec<u8>,
+const PROGRESS_STEP_BYTES: u64 = 256 * 1024;
+const CRX3_SIGNATURE_CONTEXT: &[u8] = b"CRX3 SignedData\x00";
+
+/// Proof lists of a CRX3 header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ProofAlgorithm {
+  Sha256WithRsa,
+  Sha256WithEcdsa,
 }
 
-pub(crate) fn build_update_url(base: &str, extension_id: &str, version: Option<&str>) -> String {
-  let mut x = format!("id%3D{extension_id}%26installsource%3Dondemand");
-  if let Some(version) = version {
-    x.push_str(&format!("%26v%3D{version}"));
+impl ProofAlgorithm {
+  /// Header field name, used in errors.
+  fn label(self) -> &'static str {
+    match self {
+      ProofAlgorithm::Sha256WithRsa => "sha256_with_rsa",
+      ProofAlgorithm::Sha256WithEcdsa => "sha256_with_ecdsa",
+    }
   }
-  x.push_str("%26uc");
-  format!("{base}?response=redirect&os=win&arch=x64&os_arch=x86_64&nacl_arch=x86-64&prod=chromecrx&prodchannel=unknown&prodversion=120.0.0.0&acceptformat=crx2%2Ccrx3&x={x}")
 }
 
-pub(crate) enum UpdateCheck {
-  NoUpdate,
-  UpdateAvailable(Option<Vec<u8>>),
+/// Error label of the single RSA SHA-1 signature of a CRX2.
+const CRX2_SIGNATURE_LABEL: &str = "sha1_with_rsa";
+
+/// CRX versions accepted from a source. Update2 servers only serve CRX3;
+/// CRX2 is still taken from local files and mirrors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AcceptedCrx {
+  Crx3,
+  Crx2OrCrx3,
 }
 
-pub(crate) fn check_update(url: &str) -> Result<UpdateCheck> {
-  let agent = ureq::AgentBuilder::new()
-    .timeout_connect(Duration::from_secs(10))
-    .timeout_read(Duration::from_secs(10))
-    .timeout_write(Duration::from_secs(10))
-    .redirects(0)
-    .build();
-  let response = agent
-    .get(url)
-    .call()
-    .map_err(|error| anyhow!("update check failed: {error}"))?;
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum CrxError {
+  #[error("crx too small")]
+  TooSmall,
+  #[error("invalid crx magic")]
+  InvalidMagic,
+  #[error("unsupported crx version: {0}")]
+  UnsupportedVersion(u32),
+  #[error("crx header truncated")]
+  HeaderTruncated,
+  #[error("invalid crx header: {0}")]
+  InvalidHeader(prost::DecodeError),
+  #[error("missing signed_header_data")]
+  MissingSignedHeaderData,
+  #[error("missing crx_id")]
+  MissingCrxId,
+  #[error("invalid crx_id length: {0}")]
+  InvalidCrxIdLength(usize),
+  #[error("{0} proof has no public_key")]
+  MissingPublicKey(&'static str),
+  #[error("{0} proof has no signature")]
+  MissingSignature(&'static str),
+  #[error("{0} public_key is invalid: {1}")]
+  InvalidPublicKey(&'static str, String),
+  #[error("{0} signature is malformed: {1}")]
+  InvalidSignature(&'static str, String),
+  #[error("{0} signature does not match crx contents")]
+  SignatureMismatch(&'static str),
+  #[error("no public_key matched crx_id")]
+  NoMatchingPublicKey,
+}
 
-  match response.status() {
-    204 => Ok(UpdateCheck::NoUpdate),
-    200 => {
-      let mut reader = response.into_reader();
-      let mut buffer = Vec::new();
-      reader.read_to_end(&mut buffer)?;
-      Ok(UpdateCheck::UpdateAvailable(Some(buffer)))
-    }
-    301 | 302 | 307 | 308 => Ok(UpdateCheck::UpdateAvailable(None)),
-    status => Err(anyhow!("update check failed: {}", status)),
-  }
+pub(crate) struct ParsedCrx {
+  pub(crate) public_key: Vec<u8>,
+  pub(crate) zip_bytes: Vec<u8>,
+}
+
+/// Resume state stored next to a `.part` file so a retry can send `If-Range`.
+#[derive(Deserialize, Serialize)]
+struct PartialDownload {
+  url: String,
+  validator: Option<String>,
+}
+
+fn partial_meta_path(part_path: &Path) -> PathBuf {
+  let mut name = part_path.as_os_str().to_owned();
+  name.push(".meta");
+  PathBuf::from(name)
 }
 
-pub(crate) fn download_crx(url: &str) -> Result<Vec<u8>> {
-  let agent = ureq::AgentBuilder::new()
-    .timeout_connect(Duration::from_secs(10))
-    .timeout_read(Duration::from_secs(30))
-    .timeout_write(Duration::from_secs(30))
-    .build();
+/// Streams `url` into `part_path`. If an earlier attempt left a partial file
+/// for the same URL, only the missing range is requested. `on_progress`
+/// receives the bytes on disk and the expected total, when known.
+pub(crate) fn download_crx(
+  url: &str,
+  part_path: &Path,
+  on_progress: &mut dyn FnMut(u64, Option<u64>),
+) -> Result<()> {
+  let agent = http_agent();
+  let meta_path = partial_meta_path(part_path);
+  let validator = fs::read_to_string(&meta_path)
+    .ok()
+    .and_then(|raw| serde_json::from_str::<PartialDownload>(&raw).ok())
+    .filter(|meta| meta.url == url)
+    .and_then(|meta| meta.validator);
+  let offset = match validator {
+    Some(_) => fs::metadata(part_path).map(|meta| meta.len()).unwrap_or(0),
+    None => 0,
+  };
+
   let mut current = Url::parse(url)?;
   for _ in 0..5 {
-    let response = agent
-      .get(current.as_str())
-      .call()
-      .map_err(|error| anyhow!("download failed: {error}"))?;
-
-    if response.status() == 200 {
-      let mut reader = response.into_reader();
-      let mut buffer = Vec::new();
-      reader.read_to_end(&mut buffer)?;
-      return Ok(buffer);
-    }
-
-    if response.status() == 302 || response.status() == 301 {
-      if let Some(location) = response.header("Location") {
-        current = current.join(location)?;
-        continue;
+    let mut request = agent.get(current.as_str());
+    if let (true, Some(validator)) = (offset > 0, validator.as_deref()) {
+      info!("[update] resume download at {offset} bytes");
+      request = request
+        .set("Range", &format!("bytes={offset}-"))
+        .set("If-Range", validator);
+    }
+    let response = match request.call() {
+      Ok(response) => response,
+      // The partial file already holds the whole body; the hash check decides.
+      Err(ureq::Error::Status(416, _)) if offset > 0 => return Ok(()),
+      Err(error) => return Err(anyhow!("download failed: {error}")),
+    };
+
+    match response.status() {
+      200 => return write_partial(response, url, part_path, &meta_path, None, on_progress),
+      206 => {
+        return write_partial(
+          response,
+          url,
+          part_path,
+          &meta_path,
+          Some(offset),
+          on_progress,
+        )
+      }
+      301 | 302 => {
+        if let Some(location) = response.header("Location") {
+          current = current.join(location)?;
+          continue;
+        }
       }
+      _ => {}
     }
 
     return Err(anyhow!("download failed: {}", response.status()));
@@ -112,60 +194,274 @@ pub(crate) fn download_crx(url: &str) -> Result<Vec<u8>> {
   Err(anyhow!("download failed: too many redirects"))
 }
 
-pub(crate) fn parse_crx3(bytes: &[u8]) -> Result<ParsedCrx> {
+fn write_partial(
+  response: ureq::Response,
+  url: &str,
+  part_path: &Path,
+  meta_path: &Path,
+  resume_from: Option<u64>,
+  on_progress: &mut dyn FnMut(u64, Option<u64>),
+) -> Result<()> {
+  if let Some(offset) = resume_from {
+    let range = response.header("Content-Range").unwrap_or_default();
+    if !range.starts_with(&format!("bytes {offset}-")) {
+      discard_partial_download(part_path);
+      return Err(anyhow!("download failed: unexpected Content-Range {range}"));
+    }
+  }
+
+  // Weak validators are not allowed in If-Range.
+  let validator = response
+    .header("ETag")
+    .filter(|etag| !etag.starts_with("W/"))
+    .or_else(|| response.header("Last-Modified"))
+    .map(|value| value.to_string());
+  let meta = PartialDownload {
+    url: url.to_string(),
+    validator,
+  };
+  fs::write(meta_path, serde_json::to_string(&meta)?)?;
+
+  let start = resume_from.unwrap_or(0);
+  let total = response
+    .header("Content-Length")
+    .and_then(|value| value.parse::<u64>().ok())
+    .map(|length| start + length);
+  let mut file = match resume_from {
+    Some(_) => OpenOptions::new().append(true).open(part_path)?,
+    None => File::create(part_path)?,
+  };
+  let mut reader = response.into_reader();
+  let mut buffer = vec![0u8; 64 * 1024];
+  let mut received = start;
+  let mut reported = start;
+  on_progress(received, total);
+  loop {
+    let read = reader
+      .read(&mut buffer)
+      .map_err(|error| anyhow!("download interrupted: {error}"))?;
+    if read == 0 {
+      break;
+    }
+    file.write_all(&buffer[..read])?;
+    received += read as u64;
+    if received - reported >= PROGRESS_STEP_BYTES {
+      on_progress(received, total);
+      reported = received;
+    }
+  }
+  file.flush()?;
+  on_progress(received, total);
+  Ok(())
+}
+
+pub(crate) fn discard_partial_download(part_path: &Path) {
+  let _ = fs::remove_file(part_path);
+  let _ = fs::remove_file(partial_meta_path(part_path));
+}
+
+pub(crate) fn sha256_hex(bytes: &[u8]) -> String {
+  let digest = Sha256::digest(bytes);
+  let mut hex = String::with_capacity(digest.len() * 2);
+  for byte in digest {
+    use std::fmt::Write;
+    write!(&mut hex, "{:02x}", byte).unwrap();
+  }
+  hex
+}
+
+/// Checks a downloaded payload against the size and SHA-256 advertised by the
+/// update server and returns its SHA-256 hex digest.
+pub(crate) fn verify_crx_payload(
+  bytes: &[u8],
+  expected_sha256: Option<&str>,
+  expected_size: Option<u64>,
+) -> Result<String> {
+  if let Some(expected_size) = expected_size {
+    if bytes.len() as u64 != expected_size {
+      return Err(anyhow!(
+        "crx size mismatch: expected {expected_size} bytes, got {}",
+        bytes.len()
+      ));
+    }
+  }
+  let actual = sha256_hex(bytes);
+  if let Some(expected) = expected_sha256 {
+    if !actual.eq_ignore_ascii_case(expected) {
+      return Err(anyhow!(
+        "crx sha256 mismatch: expected {expected}, got {actual}"
+      ));
+    }
+  }
+  Ok(actual)
+}
+
+/// Parses a CRX file of an `accepted` version, verifying its signatures.
+pub(crate) fn parse_crx(bytes: &[u8], accepted: AcceptedCrx) -> Result<ParsedCrx> {
   if bytes.len() < 12 {
-    return Err(anyhow!("crx too small"));
+    return Err(CrxError::TooSmall.into());
   }
 
   if &bytes[0..4] != b"Cr24" {
-    return Err(anyhow!("invalid crx magic"));
+    return Err(CrxError::InvalidMagic.into());
   }
 
-  let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
-  if version != 3 {
-    return Err(anyhow!("unsupported crx version"));
+  match read_u32(bytes, 4) {
+    2 if accepted == AcceptedCrx::Crx2OrCrx3 => parse_crx2(bytes),
+    3 => parse_crx3(bytes),
+    version => Err(CrxError::UnsupportedVersion(version).into()),
   }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+  u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// CRX2: key and signature lengths, the public key, an RSA SHA-1 signature
+/// of the zip, then the zip. The ID comes from the key as with CRX3.
+fn parse_crx2(bytes: &[u8]) -> Result<ParsedCrx> {
+  if bytes.len() < 16 {
+    return Err(CrxError::TooSmall.into());
+  }
+  let key_size = read_u32(bytes, 8) as usize;
+  let signature_size = read_u32(bytes, 12) as usize;
+  let key_end = 16usize
+    .checked_add(key_size)
+    .ok_or(CrxError::HeaderTruncated)?;
+  let signature_end = key_end
+    .checked_add(signature_size)
+    .filter(|&end| end <= bytes.len())
+    .ok_or(CrxError::HeaderTruncated)?;
+
+  let public_key = &bytes[16..key_end];
+  let signature = &bytes[key_end..signature_end];
+  let zip_bytes = &bytes[signature_end..];
+  verify_rsa_proof(
+    CRX2_SIGNATURE_LABEL,
+    public_key,
+    signature,
+    Sha1::new_with_prefix(zip_bytes),
+  )?;
 
-  let header_size = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+  Ok(ParsedCrx {
+    public_key: public_key.to_vec(),
+    zip_bytes: zip_bytes.to_vec(),
+  })
+}
+
+fn parse_crx3(bytes: &[u8]) -> Result<ParsedCrx> {
+  let header_size = read_u32(bytes, 8) as usize;
   let header_start = 12;
   let header_end = header_start + header_size;
   if bytes.len() < header_end {
-    return Err(anyhow!("crx header truncated"));
+    return Err(CrxError::HeaderTruncated.into());
   }
 
-  let header = CrxFileHeader::decode(&bytes[header_start..header_end])?;
+  let header =
+    CrxFileHeader::decode(&bytes[header_start..header_end]).map_err(CrxError::InvalidHeader)?;
   let signed_header = header
     .signed_header_data
-    .ok_or_else(|| anyhow!("missing signed_header_data"))?;
-  let signed_data = SignedData::decode(signed_header.as_slice())?;
-  let crx_id = signed_data
-    .crx_id
-    .ok_or_else(|| anyhow!("missing crx_id"))?;
+    .as_deref()
+    .ok_or(CrxError::MissingSignedHeaderData)?;
+  let signed_data = SignedData::decode(signed_header).map_err(CrxError::InvalidHeader)?;
+  let crx_id = signed_data.crx_id.ok_or(CrxError::MissingCrxId)?;
   let expected_id = format_extension_id(&crx_id);
   if expected_id.len() != 32 {
-    return Err(anyhow!("invalid crx_id length: {}", expected_id.len()));
+    return Err(CrxError::InvalidCrxIdLength(expected_id.len()).into());
   }
 
-  let mut public_key = None;
-  for proof in header
+  let zip_bytes = &bytes[header_end..];
+  let public_key = verify_crx3_proofs(&header, signed_header, zip_bytes, &expected_id)?;
+
+  Ok(ParsedCrx {
+    public_key,
+    zip_bytes: zip_bytes.to_vec(),
+  })
+}
+
+/// Verifies every proof in the header against the CRX3 signed message and
+/// returns the public key whose hash matches `expected_id`.
+fn verify_crx3_proofs(
+  header: &CrxFileHeader,
+  signed_header: &[u8],
+  zip_bytes: &[u8],
+  expected_id: &str,
+) -> Result<Vec<u8>, CrxError> {
+  let digest = crx3_signed_digest(signed_header, zip_bytes);
+  let proofs = header
     .sha256_with_rsa
     .iter()
-    .chain(header.sha256_with_ecdsa.iter())
-  {
-    if let Some(candidate) = proof.public_key.as_ref() {
-      if extension_id_from_public_key(candidate) == expected_id {
-        public_key = Some(candidate.clone());
-        break;
+    .map(|proof| (ProofAlgorithm::Sha256WithRsa, proof))
+    .chain(
+      header
+        .sha256_with_ecdsa
+        .iter()
+        .map(|proof| (ProofAlgorithm::Sha256WithEcdsa, proof)),
+    );
+
+  let mut public_key = None;
+  for (algorithm, proof) in proofs {
+    let label = algorithm.label();
+    let candidate = proof
+      .public_key
+      .as_deref()
+      .ok_or(CrxError::MissingPublicKey(label))?;
+    let signature = proof
+      .signature
+      .as_deref()
+      .ok_or(CrxError::MissingSignature(label))?;
+    match algorithm {
+      ProofAlgorithm::Sha256WithRsa => {
+        verify_rsa_proof(label, candidate, signature, digest.clone())?
       }
+      ProofAlgorithm::Sha256WithEcdsa => verify_ecdsa_proof(candidate, signature, digest.clone())?,
+    }
+    if public_key.is_none() && extension_id_from_public_key(candidate) == expected_id {
+      public_key = Some(candidate.to_vec());
     }
   }
 
-  let public_key = public_key.ok_or_else(|| anyhow!("no public_key matched crx_id"))?;
+  public_key.ok_or(CrxError::NoMatchingPublicKey)
+}
 
-  Ok(ParsedCrx {
-    public_key,
-    zip_bytes: bytes[header_end..].to_vec(),
-  })
+/// The CRX3 signed message: context, header length and header, then the zip.
+fn crx3_signed_digest(signed_header: &[u8], zip_bytes: &[u8]) -> Sha256 {
+  let mut digest = Sha256::new();
+  digest.update(CRX3_SIGNATURE_CONTEXT);
+  digest.update((signed_header.len() as u32).to_le_bytes());
+  digest.update(signed_header);
+  digest.update(zip_bytes);
+  digest
+}
+
+fn verify_rsa_proof<D>(
+  label: &'static str,
+  public_key: &[u8],
+  signature: &[u8],
+  digest: D,
+) -> Result<(), CrxError>
+where
+  D: Digest + AssociatedOid,
+  RsaVerifyingKey<D>: DigestVerifier<D, RsaSignature>,
+{
+  let key = RsaPublicKey::from_public_key_der(public_key)
+    .map_err(|error| CrxError::InvalidPublicKey(label, error.to_string()))?;
+  let signature = RsaSignature::try_from(signature)
+    .map_err(|error| CrxError::InvalidSignature(label, error.to_string()))?;
+  RsaVerifyingKey::<D>::new(key)
+    .verify_digest(digest, &signature)
+    .map_err(|_| CrxError::SignatureMismatch(label))
+}
+
+fn verify_ecdsa_proof(public_key: &[u8], signature: &[u8], digest: Sha256) -> Result<(), CrxError> {
+  let label = ProofAlgorithm::Sha256WithEcdsa.label();
+  let key = EcdsaVerifyingKey::from_public_key_der(public_key)
+    .map_err(|error| CrxError::InvalidPublicKey(label, error.to_string()))?;
+  let signature = EcdsaSignature::from_der(signature)
+    .map_err(|error| CrxError::InvalidSignature(label, error.to_string()))?;
+  key
+    .verify_digest(digest, &signature)
+    .map_err(|_| CrxError::SignatureMismatch(label))
 }
 
 fn format_extension_id(raw_id: &[u8]) -> String {
@@ -185,7 +481,135 @@ fn format_extension_id(raw_id: &[u8]) -> String {
     .collect()
 }
 
-fn extension_id_from_public_key(public_key: &[u8]) -> String {
+/// Private key for `pack_crx3`: RSA (PKCS#8 or PKCS#1) or P-256 ECDSA (PKCS#8
+/// or SEC1), as PEM or DER.
+pub(crate) enum CrxSigningKey {
+  Rsa(Box<RsaPrivateKey>),
+  Ecdsa(EcdsaSigningKey),
+}
+
+impl CrxSigningKey {
+  pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+    if let Ok(pem) = std::str::from_utf8(bytes) {
+      if pem.contains("-----BEGIN") {
+        return RsaPrivateKey::from_pkcs8_pem(pem)
+          .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+          .map(|key| Self::Rsa(Box::new(key)))
+          .or_else(|_| EcdsaSigningKey::from_pkcs8_pem(pem).map(Self::Ecdsa))
+          .or_else(|_| p256::SecretKey::from_sec1_pem(pem).map(|key| Self::Ecdsa(key.into())))
+          .map_err(|_| anyhow!("unsupported private key; expected RSA or P-256 PEM"));
+      }
+    }
+    RsaPrivateKey::from_pkcs8_der(bytes)
+      .or_else(|_| RsaPrivateKey::from_pkcs1_der(bytes))
+      .map(|key| Self::Rsa(Box::new(key)))
+      .or_else(|_| EcdsaSigningKey::from_pkcs8_der(bytes).map(Self::Ecdsa))
+      .or_else(|_| p256::SecretKey::from_sec1_der(bytes).map(|key| Self::Ecdsa(key.into())))
+      .map_err(|_| anyhow!("unsupported private key; expected RSA or P-256 DER"))
+  }
+
+  /// SubjectPublicKeyInfo DER, as stored in the CRX header and `manifest.json`.
+  pub(crate) fn public_key_der(&self) -> Result<Vec<u8>> {
+    let document = match self {
+      Self::Rsa(key) => key.to_public_key().to_public_key_der()?,
+      Self::Ecdsa(key) => key.verifying_key().to_public_key_der()?,
+    };
+    Ok(document.as_bytes().to_vec())
+  }
+
+  fn sign(&self, digest: Sha256) -> Result<AsymmetricKeyProof> {
+    let signature = match self {
+      Self::Rsa(key) => RsaSigningKey::<Sha256>::new(key.as_ref().clone())
+        .try_sign_digest(digest)?
+        .to_vec(),
+      Self::Ecdsa(key) => {
+        let signature: EcdsaSignature = key.try_sign_digest(digest)?;
+        signature.to_der().as_bytes().to_vec()
+      }
+    };
+    Ok(AsymmetricKeyProof {
+      public_key: Some(self.public_key_der()?),
+      signature: Some(signature),
+    })
+  }
+}
+
+/// Zips the extension in `dir` and signs it as a CRX3. Files and folders
+/// whose name starts with `.` are left out.
+pub(crate) fn pack_crx_dir(dir: &Path, key: &CrxSigningKey) -> Result<Vec<u8>> {
+  ExtensionManifest::load(dir)?.validate()?;
+  pack_crx3(&zip_dir(dir)?, key)
+}
+
+pub(crate) fn pack_crx3(zip_bytes: &[u8], key: &CrxSigningKey) -> Result<Vec<u8>> {
+  let public_key = key.public_key_der()?;
+  let crx_id = Sha256::digest(&public_key)[..16].to_vec();
+  let signed_header = SignedData {
+    crx_id: Some(crx_id),
+  }
+  .encode_to_vec();
+
+  let proof = key.sign(crx3_signed_digest(&signed_header, zip_bytes))?;
+
+  let mut header = CrxFileHeader {
+    signed_header_data: Some(signed_header),
+    ..CrxFileHeader::default()
+  };
+  match key {
+    CrxSigningKey::Rsa(_) => header.sha256_with_rsa.push(proof),
+    CrxSigningKey::Ecdsa(_) => header.sha256_with_ecdsa.push(proof),
+  }
+  let header = header.encode_to_vec();
+
+  let mut crx = Vec::with_capacity(12 + header.len() + zip_bytes.len());
+  crx.extend_from_slice(b"Cr24");
+  crx.extend_from_slice(&3u32.to_le_bytes());
+  crx.extend_from_slice(&(header.len() as u32).to_le_bytes());
+  crx.extend_from_slice(&header);
+  crx.extend_from_slice(zip_bytes);
+  Ok(crx)
+}
+
+fn zip_dir(dir: &Path) -> Result<Vec<u8>> {
+  let mut files = Vec::new();
+  collect_pack_files(dir, dir, &mut files)?;
+  files.sort();
+
+  let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+  let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+  for (name, path) in files {
+    writer.start_file(name, options)?;
+    let mut file = File::open(&path)?;
+    std::io::copy(&mut file, &mut writer)?;
+  }
+  Ok(writer.finish()?.into_inner())
+}
+
+/// Collects `(zip path, file path)` pairs below `dir`.
+fn collect_pack_files(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) -> Result<()> {
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    if entry.file_name().to_string_lossy().starts_with('.') {
+      continue;
+    }
+    let path = entry.path();
+    let file_type = entry.file_type()?;
+    if file_type.is_dir() {
+      collect_pack_files(root, &path, files)?;
+    } else if file_type.is_file() {
+      let name = path
+        .strip_prefix(root)?
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+      files.push((name, path));
+    }
+  }
+  Ok(())
+}
+
+pub(crate) fn extension_id_from_public_key(public_key: &[u8]) -> String {
   let digest = Sha256::digest(public_key);
   format_extension_id(&digest[..16])
 }
@@ -198,41 +622,649 @@ pub(crate) fn ensure_clean_dir(path: &Path) -> Result<()> {
   Ok(())
 }
 
-pub(crate) fn extract_zip(zip_bytes: &[u8], dest: &Path) -> Result<()> {
-  let reader = Cursor::new(zip_bytes);
-  let mut archive = ZipArchive::new(reader)?;
+/// Caps on what a CRX's zip payload may unpack to, set by `extractLimits`
+/// in config.json.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct ExtractLimits {
+  pub(crate) max_entries: usize,
+  pub(crate) max_file_size: u64,
+  pub(crate) max_total_size: u64,
+  /// Largest uncompressed to compressed ratio of an entry bigger than 1 MiB.
+  pub(crate) max_ratio: u64,
+}
+
+impl Default for ExtractLimits {
+  fn default() -> Self {
+    Self {
+      max_entries: 10_000,
+      max_file_size: 128 * 1024 * 1024,
+      max_total_size: 512 * 1024 * 1024,
+      max_ratio: 100,
+    }
+  }
+}
+
+/// Entries smaller than this are not ratio-checked; tiny files of repeated
+/// bytes compress far beyond any sane limit.
+const RATIO_CHECK_MIN_SIZE: u64 = 1024 * 1024;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ExtractError {
+  #[error("invalid zip: {0}")]
+  InvalidArchive(zip::result::ZipError),
+  #[error("zip has {0} entries, more than {1}")]
+  TooManyEntries(usize, usize),
+  #[error("zip entry {0} escapes the extension directory")]
+  UnsafePath(String),
+  #[error("zip entry {0} is a symlink")]
+  Symlink(String),
+  #[error("zip entry {0} is not a regular file or directory")]
+  SpecialFile(String),
+  #[error("zip entry {0} appears more than once")]
+  DuplicatePath(String),
+  #[error("zip entry {0} is larger than {1} bytes")]
+  FileTooLarge(String, u64),
+  #[error("zip expands to more than {0} bytes")]
+  TotalTooLarge(u64),
+  #[error("zip entry {0} compresses more than {1}:1")]
+  RatioTooHigh(String, u64),
+  #[error("write {} failed: {error}", path.display())]
+  Io {
+    path: PathBuf,
+    error: std::io::Error,
+  },
+}
+
+/// Unpacks `zip_bytes` into `dest`, rejecting the whole archive on the first
+/// entry that breaks `limits`. Paths are compared case-insensitively, as
+/// Windows would, so two entries can never write the same file.
+pub(crate) fn extract_zip(
+  zip_bytes: &[u8],
+  dest: &Path,
+  limits: &ExtractLimits,
+) -> Result<(), ExtractError> {
+  let mut archive =
+    ZipArchive::new(Cursor::new(zip_bytes)).map_err(ExtractError::InvalidArchive)?;
+  if archive.len() > limits.max_entries {
+    return Err(ExtractError::TooManyEntries(
+      archive.len(),
+      limits.max_entries,
+    ));
+  }
 
+  // Lowercased path -> whether it is a directory.
+  let mut seen: HashMap<String, bool> = HashMap::new();
+  let mut total: u64 = 0;
   for i in 0..archive.len() {
-    let mut file = archive.by_index(i)?;
-    let Some(path) = file.enclosed_name() else {
-      continue;
+    let mut file = archive.by_index(i).map_err(ExtractError::InvalidArchive)?;
+    let name = file.name().to_string();
+    let relative = file
+      .enclosed_name()
+      .filter(|path| path.components().next().is_some())
+      .ok_or_else(|| ExtractError::UnsafePath(name.clone()))?;
+    let is_dir = match file.unix_mode().map(|mode| mode & S_IFMT) {
+      Some(S_IFLNK) => return Err(ExtractError::Symlink(name)),
+      Some(S_IFDIR) => true,
+      Some(0) | Some(S_IFREG) | None => file.is_dir(),
+      Some(_) => return Err(ExtractError::SpecialFile(name)),
     };
-    let out_path = dest.join(path);
+    if !claim_zip_path(&mut seen, &relative, is_dir) {
+      return Err(ExtractError::DuplicatePath(name));
+    }
 
-    if file.name().ends_with('/') {
-      fs::create_dir_all(&out_path)?;
+    let out_path = dest.join(&relative);
+    if is_dir {
+      fs::create_dir_all(&out_path).map_err(|error| io_error(&out_path, error))?;
       continue;
     }
-
+    if file.size() > limits.max_file_size {
+      return Err(ExtractError::FileTooLarge(name, limits.max_file_size));
+    }
     if let Some(parent) = out_path.parent() {
-      fs::create_dir_all(parent)?;
+      fs::create_dir_all(parent).map_err(|error| io_error(parent, error))?;
     }
 
-    let mut out_file = fs::File::create(&out_path)?;
-    std::io::copy(&mut file, &mut out_file)?;
+    // The sizes in the zip headers are not trusted; count what is written.
+    let compressed = file.compressed_size();
+    let mut out_file = File::create(&out_path).map_err(|error| io_error(&out_path, error))?;
+    let cap = limits
+      .max_file_size
+      .min(limits.max_total_size.saturating_sub(total));
+    // Limits from the config can be `u64::MAX`.
+    let written = std::io::copy(&mut (&mut file).take(cap.saturating_add(1)), &mut out_file)
+      .map_err(|error| io_error(&out_path, error))?;
+    if written > limits.max_file_size {
+      return Err(ExtractError::FileTooLarge(name, limits.max_file_size));
+    }
+    if written > cap {
+      return Err(ExtractError::TotalTooLarge(limits.max_total_size));
+    }
+    if written > RATIO_CHECK_MIN_SIZE && written / compressed.max(1) > limits.max_ratio {
+      return Err(ExtractError::RatioTooHigh(name, limits.max_ratio));
+    }
+    total += written;
   }
 
   Ok(())
 }
 
+fn io_error(path: &Path, error: std::io::Error) -> ExtractError {
+  ExtractError::Io {
+    path: path.to_path_buf(),
+    error,
+  }
+}
+
+/// Records `path` and its parent folders. Returns false if a file and another
+/// file or folder would land on the same path.
+fn claim_zip_path(seen: &mut HashMap<String, bool>, path: &Path, is_dir: bool) -> bool {
+  let mut key = String::new();
+  let mut components = path.components().peekable();
+  while let Some(component) = components.next() {
+    if !key.is_empty() {
+      key.push('/');
+    }
+    key.push_str(&component.as_os_str().to_string_lossy().to_lowercase());
+    let entry_is_dir = components.peek().is_some() || is_dir;
+    match seen.get(&key) {
+      Some(true) if entry_is_dir => {}
+      Some(_) => return false,
+      None => {
+        seen.insert(key.clone(), entry_is_dir);
+      }
+    }
+  }
+  true
+}
+
 pub(crate) fn inject_manifest_key(extension_dir: &Path, public_key: &[u8]) -> Result<()> {
-  let manifest_path = extension_dir.join("manifest.json");
-  let raw = fs::read_to_string(&manifest_path)?;
-  let mut value: Value = serde_json::from_str(&raw)?;
-  let key = base64_standard.encode(public_key);
-  value["key"] = Value::String(key);
-  let pretty = serde_json::to_string_pretty(&value)?;
-  let mut file = fs::File::create(&manifest_path)?;
-  file.write_all(pretty.as_bytes())?;
+  let mut manifest = ExtensionManifest::load(extension_dir)?;
+  manifest.key = Some(base64_standard.encode(public_key));
+  manifest.save(extension_dir)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ManifestKeyError {
+  #[error("manifest.json has no key")]
+  Missing,
+  #[error("manifest.json key is not valid base64: {0}")]
+  InvalidBase64(base64::DecodeError),
+  #[error(
+    "manifest.json key gives extension id {actual}, expected {expected}; \
+     the extension would load under a different origin and lose its stored data"
+  )]
+  IdMismatch { expected: String, actual: String },
+}
+
+/// Checks that the `key` in `manifest.json`, as WebView2 will read it,
+/// produces `expected_id`.
+pub(crate) fn verify_manifest_key(extension_dir: &Path, expected_id: &str) -> Result<()> {
+  let manifest = ExtensionManifest::load(extension_dir)?;
+  let key = manifest.key.ok_or(ManifestKeyError::Missing)?;
+  let public_key = base64_standard
+    .decode(key.trim())
+    .map_err(ManifestKeyError::InvalidBase64)?;
+  let actual = extension_id_from_public_key(&public_key);
+  if actual != expected_id {
+    return Err(
+      ManifestKeyError::IdMismatch {
+        expected: expected_id.to_string(),
+        actual,
+      }
+      .into(),
+    );
+  }
   Ok(())
 }
+
+/// Parses a CRX that must be `extension_id` and unpacks it into `dest` with
+/// the CRX's public key written to `manifest.json`. `dest` is removed again
+/// if unpacking fails.
+pub(crate) fn unpack_crx(
+  crx_bytes: &[u8],
+  accepted: AcceptedCrx,
+  extension_id: &str,
+  dest: &Path,
+  limits: &ExtractLimits,
+) -> Result<()> {
+  let parsed = parse_crx(crx_bytes, accepted)?;
+  let crx_id = extension_id_from_public_key(&parsed.public_key);
+  if crx_id != extension_id {
+    return Err(anyhow!("crx id {crx_id} does not match {extension_id}"));
+  }
+  ensure_clean_dir(dest)?;
+  let unpacked = extract_zip(&parsed.zip_bytes, dest, limits)
+    .map_err(Into::into)
+    .and_then(|_| inject_manifest_key(dest, &parsed.public_key));
+  if unpacked.is_err() {
+    let _ = fs::remove_dir_all(dest);
+  }
+  unpacked
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rsa_key() -> CrxSigningKey {
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+    CrxSigningKey::Rsa(Box::new(key))
+  }
+
+  fn ecdsa_key() -> CrxSigningKey {
+    CrxSigningKey::Ecdsa(EcdsaSigningKey::random(&mut rand::thread_rng()))
+  }
+
+  fn test_zip() -> Vec<u8> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    writer
+      .start_file("manifest.json", SimpleFileOptions::default())
+      .unwrap();
+    writer
+      .write_all(br#"{"name":"test","version":"1.0","manifest_version":3}"#)
+      .unwrap();
+    writer.finish().unwrap().into_inner()
+  }
+
+  fn crx_id_header(key: &CrxSigningKey) -> Vec<u8> {
+    let public_key = key.public_key_der().unwrap();
+    SignedData {
+      crx_id: Some(Sha256::digest(&public_key)[..16].to_vec()),
+    }
+    .encode_to_vec()
+  }
+
+  fn write_crx3(header: &CrxFileHeader, zip_bytes: &[u8]) -> Vec<u8> {
+    let header = header.encode_to_vec();
+    let mut crx = b"Cr24".to_vec();
+    crx.extend_from_slice(&3u32.to_le_bytes());
+    crx.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    crx.extend_from_slice(&header);
+    crx.extend_from_slice(zip_bytes);
+    crx
+  }
+
+  fn read_crx3_header(crx: &[u8]) -> CrxFileHeader {
+    let header_end = 12 + read_u32(crx, 8) as usize;
+    CrxFileHeader::decode(&crx[12..header_end]).unwrap()
+  }
+
+  fn crx_error(bytes: &[u8]) -> CrxError {
+    match parse_crx(bytes, AcceptedCrx::Crx2OrCrx3) {
+      Ok(_) => panic!("crx was accepted"),
+      Err(error) => error.downcast::<CrxError>().unwrap(),
+    }
+  }
+
+  #[test]
+  fn accepts_valid_crx3() {
+    let zip = test_zip();
+    for key in [rsa_key(), ecdsa_key()] {
+      let parsed = parse_crx(&pack_crx3(&zip, &key).unwrap(), AcceptedCrx::Crx3).unwrap();
+      assert_eq!(parsed.public_key, key.public_key_der().unwrap());
+      assert_eq!(parsed.zip_bytes, zip);
+    }
+  }
+
+  #[test]
+  fn rejects_tampered_zip() {
+    for key in [rsa_key(), ecdsa_key()] {
+      let mut crx = pack_crx3(&test_zip(), &key).unwrap();
+      *crx.last_mut().unwrap() ^= 1;
+      assert!(matches!(crx_error(&crx), CrxError::SignatureMismatch(_)));
+    }
+  }
+
+  #[test]
+  fn rejects_tampered_signed_header() {
+    let zip = test_zip();
+    for key in [rsa_key(), ecdsa_key()] {
+      let mut header = read_crx3_header(&pack_crx3(&zip, &key).unwrap());
+      // An unknown field still decodes, so only the signature catches it.
+      header
+        .signed_header_data
+        .as_mut()
+        .unwrap()
+        .extend_from_slice(&[0x10, 0x01]);
+      let crx = write_crx3(&header, &zip);
+      assert!(matches!(crx_error(&crx), CrxError::SignatureMismatch(_)));
+    }
+  }
+
+  #[test]
+  fn rejects_proof_from_other_key() {
+    let zip = test_zip();
+    let (key, other) = (ecdsa_key(), rsa_key());
+    let signed_header = crx_id_header(&key);
+    let header = CrxFileHeader {
+      sha256_with_rsa: vec![other
+        .sign(crx3_signed_digest(&signed_header, &zip))
+        .unwrap()],
+      signed_header_data: Some(signed_header),
+      ..CrxFileHeader::default()
+    };
+    let crx = write_crx3(&header, &zip);
+    assert!(matches!(crx_error(&crx), CrxError::NoMatchingPublicKey));
+  }
+
+  #[test]
+  fn rejects_missing_signature() {
+    let zip = test_zip();
+    let key = ecdsa_key();
+    let signed_header = crx_id_header(&key);
+    let mut proof = key.sign(crx3_signed_digest(&signed_header, &zip)).unwrap();
+    proof.signature = None;
+    let header = CrxFileHeader {
+      sha256_with_ecdsa: vec![proof],
+      signed_header_data: Some(signed_header),
+      ..CrxFileHeader::default()
+    };
+    let crx = write_crx3(&header, &zip);
+    assert!(matches!(
+      crx_error(&crx),
+      CrxError::MissingSignature("sha256_with_ecdsa")
+    ));
+  }
+
+  fn zip_of(entries: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, contents) in entries {
+      if name.ends_with('/') {
+        writer
+          .add_directory(*name, SimpleFileOptions::default())
+          .unwrap();
+      } else {
+        writer
+          .start_file(*name, SimpleFileOptions::default())
+          .unwrap();
+        writer.write_all(contents).unwrap();
+      }
+    }
+    writer.finish().unwrap().into_inner()
+  }
+
+  fn extract(zip_bytes: &[u8], limits: &ExtractLimits) -> Result<(), ExtractError> {
+    let dir = tempfile::tempdir().unwrap();
+    extract_zip(zip_bytes, dir.path(), limits)
+  }
+
+  #[test]
+  fn extracts_with_unbounded_limits() {
+    let limits = ExtractLimits {
+      max_entries: usize::MAX,
+      max_file_size: u64::MAX,
+      max_total_size: u64::MAX,
+      max_ratio: u64::MAX,
+    };
+    let dir = tempfile::tempdir().unwrap();
+    extract_zip(&test_zip(), dir.path(), &limits).unwrap();
+    assert!(dir.path().join("manifest.json").is_file());
+  }
+
+  #[test]
+  fn rejects_paths_outside_the_destination() {
+    for name in ["../evil.js", "a/../../evil.js", "/evil.js"] {
+      let zip = zip_of(&[(name, b"x")]);
+      let error = extract(&zip, &ExtractLimits::default()).unwrap_err();
+      assert!(matches!(error, ExtractError::UnsafePath(_)), "{name}");
+    }
+  }
+
+  #[test]
+  fn rejects_symlinks() {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    writer
+      .add_symlink("link", "/etc/passwd", SimpleFileOptions::default())
+      .unwrap();
+    let zip = writer.finish().unwrap().into_inner();
+    let error = extract(&zip, &ExtractLimits::default()).unwrap_err();
+    assert!(matches!(error, ExtractError::Symlink(_)));
+  }
+
+  #[test]
+  fn rejects_device_entries() {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().unix_permissions(0o644);
+    writer.start_file("dev", options).unwrap();
+    let mut zip = writer.finish().unwrap().into_inner();
+    // The writer only keeps permission bits, so the central directory's
+    // external attributes are rewritten to a character device.
+    let central = zip
+      .windows(4)
+      .position(|bytes| bytes == b"PK\x01\x02")
+      .unwrap();
+    zip[central + 38..central + 42].copy_from_slice(&((0o020644u32) << 16).to_le_bytes());
+    let error = extract(&zip, &ExtractLimits::default()).unwrap_err();
+    assert!(matches!(error, ExtractError::SpecialFile(_)));
+  }
+
+  #[test]
+  fn rejects_case_insensitive_duplicates() {
+    let zip = zip_of(&[("Scripts/a.js", b"1"), ("scripts/A.JS", b"2")]);
+    let error = extract(&zip, &ExtractLimits::default()).unwrap_err();
+    assert!(matches!(error, ExtractError::DuplicatePath(_)));
+  }
+
+  #[test]
+  fn rejects_a_file_where_a_folder_is_needed() {
+    let zip = zip_of(&[("a", b"file"), ("a/b.js", b"x")]);
+    let error = extract(&zip, &ExtractLimits::default()).unwrap_err();
+    assert!(matches!(error, ExtractError::DuplicatePath(_)));
+
+    let zip = zip_of(&[("a/", b""), ("A", b"file")]);
+    let error = extract(&zip, &ExtractLimits::default()).unwrap_err();
+    assert!(matches!(error, ExtractError::DuplicatePath(_)));
+  }
+
+  #[test]
+  fn rejects_too_many_entries() {
+    let zip = zip_of(&[("a.js", b"1"), ("b.js", b"2"), ("c.js", b"3")]);
+    let limits = ExtractLimits {
+      max_entries: 2,
+      ..ExtractLimits::default()
+    };
+    let error = extract(&zip, &limits).unwrap_err();
+    assert!(matches!(error, ExtractError::TooManyEntries(3, 2)));
+  }
+
+  #[test]
+  fn rejects_oversized_files_and_totals() {
+    let limits = ExtractLimits {
+      max_file_size: 8,
+      max_total_size: 12,
+      ..ExtractLimits::default()
+    };
+    let zip = zip_of(&[("big.js", b"123456789")]);
+    let error = extract(&zip, &limits).unwrap_err();
+    assert!(matches!(error, ExtractError::FileTooLarge(_, 8)));
+
+    let zip = zip_of(&[("a.js", b"12345678"), ("b.js", b"12345678")]);
+    let error = extract(&zip, &limits).unwrap_err();
+    assert!(matches!(error, ExtractError::TotalTooLarge(12)));
+  }
+
+  #[test]
+  fn rejects_high_ratio_entries() {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    writer.start_file("bomb.js", options).unwrap();
+    writer.write_all(&vec![0; 4 * 1024 * 1024]).unwrap();
+    let zip = writer.finish().unwrap().into_inner();
+    let error = extract(&zip, &ExtractLimits::default()).unwrap_err();
+    assert!(matches!(error, ExtractError::RatioTooHigh(_, 100)));
+  }
+
+  fn assert_round_trip(key_bytes: &[u8], expected: &CrxSigningKey) {
+    let key = CrxSigningKey::from_bytes(key_bytes).unwrap();
+    let zip = test_zip();
+    let parsed = parse_crx(&pack_crx3(&zip, &key).unwrap(), AcceptedCrx::Crx3).unwrap();
+    assert_eq!(parsed.public_key, expected.public_key_der().unwrap());
+    assert_eq!(parsed.zip_bytes, zip);
+  }
+
+  #[test]
+  fn packs_with_rsa_keys() {
+    use rsa::pkcs1::EncodeRsaPrivateKey;
+    use rsa::pkcs8::EncodePrivateKey;
+
+    let key = rsa_key();
+    let CrxSigningKey::Rsa(rsa) = &key else {
+      unreachable!()
+    };
+    let pkcs1 = rsa.to_pkcs1_pem(Default::default()).unwrap();
+    assert_round_trip(pkcs1.as_bytes(), &key);
+    assert_round_trip(rsa.to_pkcs1_der().unwrap().as_bytes(), &key);
+    let pkcs8 = rsa.to_pkcs8_pem(Default::default()).unwrap();
+    assert_round_trip(pkcs8.as_bytes(), &key);
+    assert_round_trip(rsa.to_pkcs8_der().unwrap().as_bytes(), &key);
+  }
+
+  #[test]
+  fn packs_with_p256_keys() {
+    use p256::pkcs8::EncodePrivateKey;
+
+    let key = ecdsa_key();
+    let CrxSigningKey::Ecdsa(ecdsa) = &key else {
+      unreachable!()
+    };
+    let secret = p256::SecretKey::from(ecdsa);
+    let sec1 = secret.to_sec1_pem(Default::default()).unwrap();
+    assert_round_trip(sec1.as_bytes(), &key);
+    assert_round_trip(&secret.to_sec1_der().unwrap(), &key);
+    let pkcs8 = secret.to_pkcs8_pem(Default::default()).unwrap();
+    assert_round_trip(pkcs8.as_bytes(), &key);
+    assert_round_trip(secret.to_pkcs8_der().unwrap().as_bytes(), &key);
+  }
+
+  #[test]
+  fn packs_a_folder_without_dot_files() {
+    let source = tempfile::tempdir().unwrap();
+    let manifest = r#"{"name":"helper","version":"1.0","manifest_version":3}"#;
+    fs::write(source.path().join("manifest.json"), manifest).unwrap();
+    fs::create_dir(source.path().join("js")).unwrap();
+    fs::write(source.path().join("js/main.js"), "main()").unwrap();
+    fs::create_dir(source.path().join(".git")).unwrap();
+    fs::write(source.path().join(".git/HEAD"), "ref").unwrap();
+
+    let key = ecdsa_key();
+    let parsed = parse_crx(
+      &pack_crx_dir(source.path(), &key).unwrap(),
+      AcceptedCrx::Crx3,
+    )
+    .unwrap();
+    assert_eq!(parsed.public_key, key.public_key_der().unwrap());
+    let dest = tempfile::tempdir().unwrap();
+    extract_zip(&parsed.zip_bytes, dest.path(), &ExtractLimits::default()).unwrap();
+    assert_eq!(
+      fs::read_to_string(dest.path().join("manifest.json")).unwrap(),
+      manifest
+    );
+    assert_eq!(
+      fs::read_to_string(dest.path().join("js/main.js")).unwrap(),
+      "main()"
+    );
+    assert!(!dest.path().join(".git").exists());
+  }
+
+  #[test]
+  fn unpacks_only_the_expected_extension() {
+    let key = ecdsa_key();
+    let crx = pack_crx3(&test_zip(), &key).unwrap();
+    let id = extension_id_from_public_key(&key.public_key_der().unwrap());
+    let root = tempfile::tempdir().unwrap();
+    let dest = root.path().join("staging");
+
+    let other_id = "a".repeat(32);
+    let error = unpack_crx(
+      &crx,
+      AcceptedCrx::Crx3,
+      &other_id,
+      &dest,
+      &ExtractLimits::default(),
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("does not match"), "{error:#}");
+    assert!(!dest.exists());
+
+    unpack_crx(
+      &crx,
+      AcceptedCrx::Crx3,
+      &id,
+      &dest,
+      &ExtractLimits::default(),
+    )
+    .unwrap();
+    verify_manifest_key(&dest, &id).unwrap();
+  }
+
+  fn write_crx2(key: &RsaPrivateKey, zip_bytes: &[u8]) -> Vec<u8> {
+    let public_key = key.to_public_key().to_public_key_der().unwrap();
+    let signature = RsaSigningKey::<Sha1>::new(key.clone())
+      .try_sign_digest(Sha1::new_with_prefix(zip_bytes))
+      .unwrap()
+      .to_vec();
+    let mut crx = b"Cr24".to_vec();
+    crx.extend_from_slice(&2u32.to_le_bytes());
+    crx.extend_from_slice(&(public_key.as_bytes().len() as u32).to_le_bytes());
+    crx.extend_from_slice(&(signature.len() as u32).to_le_bytes());
+    crx.extend_from_slice(public_key.as_bytes());
+    crx.extend_from_slice(&signature);
+    crx.extend_from_slice(zip_bytes);
+    crx
+  }
+
+  fn rsa_crx2() -> (Vec<u8>, Vec<u8>) {
+    let key = rsa_key();
+    let CrxSigningKey::Rsa(rsa) = &key else {
+      unreachable!()
+    };
+    (write_crx2(rsa, &test_zip()), key.public_key_der().unwrap())
+  }
+
+  #[test]
+  fn accepts_valid_crx2_only_where_allowed() {
+    let (crx, public_key) = rsa_crx2();
+    let parsed = parse_crx(&crx, AcceptedCrx::Crx2OrCrx3).unwrap();
+    assert_eq!(parsed.public_key, public_key);
+    assert_eq!(parsed.zip_bytes, test_zip());
+
+    let error = match parse_crx(&crx, AcceptedCrx::Crx3) {
+      Ok(_) => panic!("crx2 was accepted"),
+      Err(error) => error.downcast::<CrxError>().unwrap(),
+    };
+    assert!(matches!(error, CrxError::UnsupportedVersion(2)));
+  }
+
+  #[test]
+  fn rejects_crx2_with_bad_signature() {
+    let (mut crx, _) = rsa_crx2();
+    let last = crx.len() - 1;
+    crx[last] ^= 0xff;
+    assert!(matches!(
+      crx_error(&crx),
+      CrxError::SignatureMismatch("sha1_with_rsa")
+    ));
+  }
+
+  #[test]
+  fn rejects_truncated_crx2_lengths() {
+    let (crx, _) = rsa_crx2();
+    for offset in [8, 12] {
+      let mut truncated = crx.clone();
+      truncated[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+      assert!(matches!(crx_error(&truncated), CrxError::HeaderTruncated));
+    }
+    assert!(matches!(crx_error(&crx[..14]), CrxError::TooSmall));
+    let key_size = read_u32(&crx, 8) as usize;
+    assert!(matches!(
+      crx_error(&crx[..16 + key_size + 10]),
+      CrxError::HeaderTruncated
+    ));
+  }
+}
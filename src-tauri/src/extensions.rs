@@ -1,17 +1,39 @@
-use crate::config::load_config;
+use crate::config::{load_config, resolve_seed_crx, AppConfig};
 use crate::crx::{
-  build_update_url, check_update, download_crx, ensure_clean_dir, extract_zip, inject_manifest_key,
-  parse_crx3, UpdateCheck,
+  extension_id_from_public_key, parse_crx, sha256_hex, unpack_crx, verify_manifest_key,
+  AcceptedCrx, ExtractLimits,
+};
+use crate::integrity::{record_file_hashes, verify_file_hashes};
+use crate::line_versions::{activate_line_version, archive_line_dir};
+use crate::manifest::{ExtensionManifest, PermissionDiff};
+use crate::patches::{
+  apply_patches, find_session_clear_calls, load_patch_rules, PatchReport, UnpatchedSessionClear,
+};
+use crate::paths::extensions_root;
+use crate::progress::{unix_now_millis, ExtensionProgress, ProgressReporter};
+use crate::retry::{
+  retry_with_backoff, RetryCancelled, RetryPolicy, RetryWait, UseCachedRequested,
+};
+use crate::settings::{load_settings, save_settings, AppSettings};
+use crate::update2::compare_versions;
+use crate::update_sources::{download_update, DownloadedUpdate, UpdateSource};
+use crate::user_extensions::{
+  apply_pending_user_removals, apply_store_updates, user_extensions_dir,
 };
-use crate::paths::app_data_root;
 use anyhow::{anyhow, Result};
 use log::{debug, info, warn};
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
+use tauri_plugin_dialog::{
+  DialogExt, MessageDialogButtons, MessageDialogKind, MessageDialogResult,
+};
 use url::Url;
 
+#[cfg(target_os = "windows")]
+use crate::user_extensions::collect_user_extension_dirs;
 #[cfg(target_os = "windows")]
 use crate::windowing::{attach_new_window_handler, attach_permission_handler};
 #[cfg(target_os = "windows")]
@@ -25,118 +47,1093 @@ use webview2_com::Microsoft::Web::WebView2::Win32::{
 };
 #[cfg(target_os = "windows")]
 use webview2_com::{
-  take_pwstr, wait_with_pump, BrowserExtensionEnableCompletedHandler, GetCookiesCompletedHandler,
-  ProfileAddBrowserExtensionCompletedHandler,
+  take_pwstr, wait_with_pump, BrowserExtensionEnableCompletedHandler,
+  BrowserExtensionRemoveCompletedHandler, GetCookiesCompletedHandler,
+  ProfileAddBrowserExtensionCompletedHandler, ProfileGetBrowserExtensionsCompletedHandler,
 };
 #[cfg(target_os = "windows")]
 use windows::core::BOOL;
 #[cfg(target_os = "windows")]
 use windows::core::{Interface, HSTRING, PCWSTR, PWSTR};
 
+pub(crate) const LINE_STAGING_DIR: &str = "line.staging";
+const LINE_PREVIOUS_DIR: &str = "line.previous";
+const LINE_DOWNLOAD_PART: &str = "line.crx.part";
+const LINE_READY_DIR: &str = "line.ready";
+const LINE_READY_RECORD: &str = "line.ready.json";
+/// The CRX `line` was installed from, for reinstalling it when its files change.
+const LINE_CACHED_CRX: &str = "line.crx";
+const LINE_READY_CRX: &str = "line.ready.crx";
+
+pub(crate) fn line_dir(extensions_root: &Path) -> PathBuf {
+  extensions_root.join("line")
+}
+
 pub(crate) struct ExtensionSetup {
   pub(crate) line_dir: PathBuf,
   pub(crate) user_dir: PathBuf,
   pub(crate) updated: bool,
   pub(crate) update_failed: bool,
+  /// Session-clearing calls that survived patching in a freshly installed version.
+  pub(crate) unpatched_clear_calls: Vec<String>,
+}
+
+/// What a staged LINE extension must satisfy before it replaces the active one.
+struct InstallCheck<'a> {
+  extension_id: &'a str,
+  /// Refuse versions in which session-clearing calls survived patching.
+  refuse_unpatched_clear: bool,
+  /// Asks before a version with new permissions replaces the installed one;
+  /// `None` when the user chose to always allow.
+  permission_prompt: Option<&'a tauri::AppHandle>,
+  extract_limits: ExtractLimits,
+}
+
+impl<'a> InstallCheck<'a> {
+  fn new(app: &'a tauri::AppHandle, config: &'a AppConfig, settings: &AppSettings) -> Self {
+    Self {
+      extension_id: &config.line_extension_id,
+      refuse_unpatched_clear: settings.refuse_unpatched_session_clear,
+      permission_prompt: (!settings.always_allow_line_permissions).then_some(app),
+      extract_limits: config.extract_limits,
+    }
+  }
 }
 
 pub(crate) fn prepare_extensions(app: &tauri::AppHandle) -> Result<ExtensionSetup> {
-  let config = load_config(app)?;
-  let app_data = app_data_root(app)?;
+  let mut setup = select_line_extension(app)?;
+  let extensions_root = extensions_root(app)?;
+  setup.unpatched_clear_calls = take_session_clear_warning(&extensions_root);
+  Ok(setup)
+}
+
+/// Returns the unpatched calls recorded by the latest install, once.
+fn take_session_clear_warning(extensions_root: &Path) -> Vec<String> {
+  let Some(mut record) = read_install_record(extensions_root) else {
+    return Vec::new();
+  };
+  if record.unpatched_clear_calls.is_empty() || record.unpatched_clear_warned {
+    return Vec::new();
+  }
+  record.unpatched_clear_warned = true;
+  if let Err(error) = write_install_record(extensions_root, &record) {
+    warn!("[update] install record write failed: {error:#}");
+  }
+  record.unpatched_clear_calls
+}
 
-  let extensions_root = app_data.join("extensions");
-  let line_dir = extensions_root.join("line");
-  let user_dir = extensions_root.join("user");
+fn select_line_extension(app: &tauri::AppHandle) -> Result<ExtensionSetup> {
+  let config = load_config(app)?;
+  let progress = ProgressReporter::new(app);
+  let extensions_root = extensions_root(app)?;
+  let line_dir = line_dir(&extensions_root);
+  let user_dir = user_extensions_dir(&extensions_root);
 
   info!("[update] storage root={}", extensions_root.display());
   fs::create_dir_all(&user_dir)?;
+  apply_pending_user_removals(&extensions_root);
+  apply_store_updates(&extensions_root);
+  recover_interrupted_install(&extensions_root, &line_dir);
+
+  let mut settings = load_settings(app).unwrap_or_default();
+  let check = InstallCheck::new(app, &config, &settings);
+  match install_pending_crx(&extensions_root, &line_dir, &check) {
+    Ok(Some(record)) => {
+      // A CRX the user picked wins over an update staged in the background.
+      discard_ready_update(&extensions_root);
+      if settings.pinned_line_version.is_some() {
+        settings.pinned_line_version = record.version.clone();
+        if let Err(error) = save_settings(app, &settings) {
+          warn!("[update] pin save failed: {error:#}");
+        }
+      }
+      return Ok(use_local(&extensions_root, line_dir, user_dir, false));
+    }
+    Ok(None) => {}
+    Err(error) => {
+      warn!("[update] local crx install failed: {error:#}");
+      let message = format!("CRX ファイルをインストールできませんでした。\n{error:#}");
+      let dialog_handle = app.clone();
+      let _ = app.run_on_main_thread(move || {
+        dialog_handle
+          .dialog()
+          .message(message)
+          .title("インストール失敗")
+          .kind(MessageDialogKind::Error)
+          .show(|_| {});
+      });
+    }
+  }
+
+  if let Some(pinned) = settings.pinned_line_version.as_deref() {
+    if read_manifest_version(&line_dir).as_deref() != Some(pinned) {
+      if let Err(error) = activate_line_version(&extensions_root, &line_dir, pinned) {
+        warn!("[update] activate pinned v{pinned} failed: {error:#}");
+      }
+    }
+    verify_line_extension(&extensions_root, &line_dir, &check, None);
+    if is_extension_dir(&line_dir) {
+      log_local_extension(
+        &extensions_root,
+        read_manifest_version(&line_dir).as_deref(),
+        "pinned",
+      );
+      return Ok(use_local(&extensions_root, line_dir, user_dir, false));
+    }
+    warn!("[update] pinned v{pinned} unavailable, checking for updates");
+  } else {
+    if let Err(error) = apply_ready_update(&extensions_root, &line_dir, &check) {
+      warn!("[update] staged update failed: {error:#}");
+    }
+    verify_line_extension(
+      &extensions_root,
+      &line_dir,
+      &check,
+      Some((&config, &progress)),
+    );
+  }
+
+  if is_extension_dir(&line_dir) && settings.pinned_line_version.is_none() {
+    log_local_extension(
+      &extensions_root,
+      read_manifest_version(&line_dir).as_deref(),
+      "cached",
+    );
+    return Ok(use_local(&extensions_root, line_dir, user_dir, false));
+  }
+
+  // No usable local copy. Each source is checked once; if none answers, the
+  // seed is installed right away instead of after the retries.
+  let error = match update_line_extension(
+    &config,
+    &check,
+    &extensions_root,
+    line_dir.clone(),
+    user_dir.clone(),
+    &progress,
+    false,
+  ) {
+    Ok(setup) => return Ok(setup),
+    Err(error) => error,
+  };
+  warn!("[update] no local extension: {error:#}");
+  match install_seed_crx(app, &config, &check, &extensions_root, &line_dir) {
+    Ok(_) => return Ok(use_local(&extensions_root, line_dir, user_dir, true)),
+    Err(seed_error) => warn!("[update] seed install failed: {seed_error:#}"),
+  }
+  update_line_extension(
+    &config,
+    &check,
+    &extensions_root,
+    line_dir,
+    user_dir,
+    &progress,
+    true,
+  )
+}
+
+enum SourceOutcome {
+  UpToDate,
+  Installed,
+}
+
+/// Where a downloaded update goes: straight into `line_dir`, or into
+/// `line.ready` while WebView2 still has `line_dir` open.
+#[derive(Clone, Copy)]
+enum InstallTarget {
+  Active,
+  /// Like `Active`, for a `line_dir` whose files were modified.
+  Repair,
+  NextLaunch,
+}
+
+/// What `swap_in_staged` does with the install it replaces.
+#[derive(Clone, Copy)]
+pub(crate) enum Replaced {
+  /// Moved to `versions/` so it can be rolled back to.
+  Archive,
+  /// Deleted, so a modified install never overwrites a good archive.
+  Discard,
+}
 
+fn update_line_extension(
+  config: &AppConfig,
+  check: &InstallCheck,
+  extensions_root: &Path,
+  line_dir: PathBuf,
+  user_dir: PathBuf,
+  progress: &ProgressReporter,
+  retry: bool,
+) -> Result<ExtensionSetup> {
   let current_version = read_manifest_version(&line_dir);
-  let update_url = build_update_url(
-    &config.update2_base_url,
-    &config.line_extension_id,
-    current_version.as_deref(),
-  );
   let has_existing = is_extension_dir(&line_dir);
 
-  let mut updated = false;
-  let mut update_failed = false;
-  let mut crx_bytes: Option<Vec<u8>> = None;
-
-  if let Some(version) = current_version.as_deref() {
-    info!("[update] check v{} {}", version, update_url);
-    match check_update(&update_url) {
-      Ok(UpdateCheck::NoUpdate) => {
-        if has_existing {
-          info!("[update] use local extension (v{})", version);
-          let _ = disable_cache_clear(&line_dir);
-          let _ = disable_legacy_clear(&line_dir);
-          return Ok(ExtensionSetup {
-            line_dir,
-            user_dir,
-            updated: false,
-            update_failed: false,
+  let update = || {
+    update_from_sources(
+      config,
+      check,
+      extensions_root,
+      &line_dir,
+      current_version.as_deref(),
+      progress,
+      InstallTarget::Active,
+    )
+  };
+  // `retry` repeats the whole source list, so the wait does not grow with
+  // the number of sources.
+  let result = if retry {
+    retry_with_wait("update check", progress, false, update)
+  } else {
+    update()
+  };
+  match result {
+    Ok((_, SourceOutcome::UpToDate)) => {
+      log_local_extension(extensions_root, current_version.as_deref(), "up to date");
+      Ok(use_local(extensions_root, line_dir, user_dir, false))
+    }
+    Ok((source, SourceOutcome::Installed)) => {
+      info!("[update] updated from {source}");
+      Ok(ExtensionSetup {
+        line_dir,
+        user_dir,
+        updated: has_existing,
+        update_failed: false,
+        unpatched_clear_calls: Vec::new(),
+      })
+    }
+    Err(error) if error.is::<UseCachedRequested>() || error.is::<RetryCancelled>() => {
+      info!("[update] {error}");
+      fallback_to_local(extensions_root, line_dir, user_dir, has_existing)
+    }
+    Err(error) if error.is::<PermissionsDeclined>() => {
+      info!("[update] {error}");
+      Ok(use_local(extensions_root, line_dir, user_dir, false))
+    }
+    Err(error) => {
+      warn!("[update] {error:#}");
+      fallback_to_local(extensions_root, line_dir, user_dir, has_existing)
+    }
+  }
+}
+
+/// Errors that another attempt or source would not change: a choice the user
+/// made, or a version refused for what it contains.
+fn ends_update(error: &anyhow::Error) -> bool {
+  error.is::<UseCachedRequested>()
+    || error.is::<RetryCancelled>()
+    || error.is::<PermissionsDeclined>()
+    || error.is::<UnpatchedSessionClear>()
+}
+
+/// Tries each update source in config order until one answers. An
+/// `ends_update` error ends the round, since another source would not change it.
+fn update_from_sources(
+  config: &AppConfig,
+  check: &InstallCheck,
+  extensions_root: &Path,
+  line_dir: &Path,
+  current_version: Option<&str>,
+  progress: &ProgressReporter,
+  target: InstallTarget,
+) -> Result<(UpdateSource, SourceOutcome)> {
+  let mut last_error = None;
+  for source in config.update_sources() {
+    let result = update_from_source(
+      check,
+      &source,
+      extensions_root,
+      line_dir,
+      current_version,
+      progress,
+      target,
+    );
+    match result {
+      Ok(outcome) => return Ok((source, outcome)),
+      Err(error) if ends_update(&error) => return Err(error),
+      Err(error) => {
+        warn!("[update] {source} failed: {error:#}");
+        last_error = Some(error);
+      }
+    }
+  }
+  Err(last_error.unwrap_or_else(|| anyhow!("no update sources configured")))
+}
+
+fn update_from_source(
+  check: &InstallCheck,
+  source: &UpdateSource,
+  extensions_root: &Path,
+  line_dir: &Path,
+  current_version: Option<&str>,
+  progress: &ProgressReporter,
+  target: InstallTarget,
+) -> Result<SourceOutcome> {
+  let has_existing = is_extension_dir(line_dir);
+  let skip_version = |version: &str| {
+    if !has_existing {
+      return false;
+    }
+    let record = read_install_record(extensions_root).unwrap_or_default();
+    if record.declined_version.as_deref() == Some(version) {
+      info!("[update] v{version} was declined for its permissions");
+      return true;
+    }
+    if check.refuse_unpatched_clear && record.refused_version.as_deref() == Some(version) {
+      info!("[update] v{version} was refused for unpatched session clearing calls");
+      return true;
+    }
+    false
+  };
+  let Some(DownloadedUpdate {
+    version,
+    crx_bytes,
+    crx_sha256,
+  }) = download_update(
+    source,
+    check.extension_id,
+    current_version,
+    extensions_root,
+    &extensions_root.join(LINE_DOWNLOAD_PART),
+    progress,
+    skip_version,
+  )?
+  else {
+    return Ok(SourceOutcome::UpToDate);
+  };
+  progress.emit(ExtensionProgress::Installing {
+    version: Some(version.clone()),
+  });
+  let source_label = source.to_string();
+  let replaced = match target {
+    InstallTarget::Repair => Replaced::Discard,
+    _ => Replaced::Archive,
+  };
+  let installed = match target {
+    InstallTarget::Active | InstallTarget::Repair => install_line_crx(
+      extensions_root,
+      line_dir,
+      &crx_bytes,
+      source.accepted_crx(),
+      check,
+      &source_label,
+      replaced,
+    ),
+    InstallTarget::NextLaunch => stage_ready_crx(
+      extensions_root,
+      &crx_bytes,
+      &crx_sha256,
+      source.accepted_crx(),
+      check,
+      &source_label,
+    ),
+  };
+  if let Err(error) = installed {
+    if has_existing && error.is::<UnpatchedSessionClear>() {
+      remember_refused_version(extensions_root, line_dir, &version);
+    }
+    return Err(error);
+  }
+  Ok(SourceOutcome::Installed)
+}
+
+/// Records a version refused for unpatched session clearing calls, so it is
+/// not downloaded again while refusing stays on.
+fn remember_refused_version(extensions_root: &Path, line_dir: &Path, version: &str) {
+  let mut record = read_install_record(extensions_root).unwrap_or_else(|| InstallRecord {
+    version: read_manifest_version(line_dir),
+    ..InstallRecord::default()
+  });
+  record.refused_version = Some(version.to_string());
+  if let Err(error) = write_install_record(extensions_root, &record) {
+    warn!("[update] install record write failed: {error:#}");
+  }
+}
+
+/// Looks for a newer LINE extension while the current one is open. An update
+/// is staged for the next launch and the user is offered a restart.
+pub(crate) fn check_line_update(app: &tauri::AppHandle) {
+  match stage_line_update(app) {
+    Ok(Some(version)) => {
+      let dialog_handle = app.clone();
+      let _ = app.run_on_main_thread(move || {
+        let app_handle = dialog_handle.clone();
+        dialog_handle
+          .dialog()
+          .message(format!(
+            "拡張機能 v{version} を更新しました。再起動しますか？"
+          ))
+          .title("更新完了")
+          .buttons(MessageDialogButtons::YesNo)
+          .show(move |confirmed| {
+            if confirmed {
+              app_handle.restart();
+            }
           });
-        }
+      });
+    }
+    Ok(None) => {}
+    Err(error) => {
+      warn!("[update] background update failed: {error:#}");
+      if let Some(refused) = error.downcast_ref::<UnpatchedSessionClear>() {
+        let message = format!(
+          "新しい拡張機能はログイン状態を消去する処理を無効化できなかったため、適用しませんでした。\n\n{}",
+          refused.0.join("\n")
+        );
+        let dialog_handle = app.clone();
+        let _ = app.run_on_main_thread(move || {
+          dialog_handle
+            .dialog()
+            .message(message)
+            .title("更新を中止しました")
+            .show(|_| {});
+        });
       }
-      Ok(UpdateCheck::UpdateAvailable(payload)) => {
-        info!("[update] update available");
-        updated = has_existing;
-        crx_bytes = payload;
+    }
+  }
+}
+
+fn stage_line_update(app: &tauri::AppHandle) -> Result<Option<String>> {
+  let config = load_config(app)?;
+  let settings = load_settings(app).unwrap_or_default();
+  let check = InstallCheck::new(app, &config, &settings);
+  let progress = ProgressReporter::new(app);
+  let extensions_root = extensions_root(app)?;
+  let line_dir = line_dir(&extensions_root);
+  let ready_dir = extensions_root.join(LINE_READY_DIR);
+
+  // Compare against an update that is already waiting so it isn't fetched twice.
+  let current_version = match (
+    read_manifest_version(&line_dir),
+    read_manifest_version(&ready_dir),
+  ) {
+    (Some(current), Some(ready)) if compare_versions(&ready, &current) == Ordering::Greater => {
+      Some(ready)
+    }
+    (current, _) => current,
+  };
+
+  let result = retry_with_wait("update check", &progress, true, || {
+    update_from_sources(
+      &config,
+      &check,
+      &extensions_root,
+      &line_dir,
+      current_version.as_deref(),
+      &progress,
+      InstallTarget::NextLaunch,
+    )
+  });
+  match result {
+    Ok((_, SourceOutcome::UpToDate)) => Ok(None),
+    Ok((source, SourceOutcome::Installed)) => {
+      info!("[update] staged update from {source}");
+      Ok(read_manifest_version(&ready_dir))
+    }
+    Err(error) if error.is::<UseCachedRequested>() || error.is::<RetryCancelled>() => Ok(None),
+    Err(error) => Err(error),
+  }
+}
+
+/// Installs the CRX bundled via `seedCrx` in config.json. The setting may point
+/// at a single file or a directory of CRX files, tried newest version first
+/// by the version in the file name (`line-3.7.1.crx`).
+fn install_seed_crx(
+  app: &tauri::AppHandle,
+  config: &AppConfig,
+  check: &InstallCheck,
+  extensions_root: &Path,
+  line_dir: &Path,
+) -> Result<InstallRecord> {
+  let seed = resolve_seed_crx(app, config)?.ok_or_else(|| anyhow!("no seed crx configured"))?;
+  let mut candidates = Vec::new();
+  if seed.is_dir() {
+    for entry in fs::read_dir(&seed)? {
+      let path = entry?.path();
+      if path.extension().and_then(|ext| ext.to_str()) == Some("crx") {
+        candidates.push(path);
       }
+    }
+    sort_newest_seed_first(&mut candidates);
+  } else if seed.is_file() {
+    candidates.push(seed.clone());
+  }
+  if candidates.is_empty() {
+    return Err(anyhow!("no seed crx found at {}", seed.display()));
+  }
+
+  let mut last_error = None;
+  for candidate in candidates {
+    info!("[update] install seed {}", candidate.display());
+    let result = fs::read(&candidate).map_err(Into::into).and_then(|bytes| {
+      install_line_crx(
+        extensions_root,
+        line_dir,
+        &bytes,
+        AcceptedCrx::Crx2OrCrx3,
+        check,
+        "seed",
+        Replaced::Archive,
+      )
+    });
+    match result {
+      Ok(record) => return Ok(record),
       Err(error) => {
-        warn!("[update] check failed: {error:#}");
+        warn!("[update] seed {} rejected: {error:#}", candidate.display());
+        last_error = Some(error);
+      }
+    }
+  }
+  Err(last_error.unwrap_or_else(|| anyhow!("no seed crx installed")))
+}
+
+fn sort_newest_seed_first(candidates: &mut [PathBuf]) {
+  candidates.sort_by(|a, b| compare_versions(&seed_version(b), &seed_version(a)));
+}
+
+/// The version in a seed file name: the stem from its first digit on.
+fn seed_version(path: &Path) -> String {
+  let stem = path
+    .file_stem()
+    .map(|stem| stem.to_string_lossy().into_owned())
+    .unwrap_or_default();
+  match stem.find(|c: char| c.is_ascii_digit()) {
+    Some(start) => stem[start..].to_string(),
+    None => String::new(),
+  }
+}
+
+fn pending_crx_path(extensions_root: &Path) -> PathBuf {
+  extensions_root.join("line.pending.crx")
+}
+
+/// Validates a local CRX file and queues it for installation on the next
+/// `prepare_extensions`, before WebView2 holds the extension files open.
+pub(crate) fn queue_local_crx(app: &tauri::AppHandle, crx_path: &Path) -> Result<()> {
+  let config = load_config(app)?;
+  let bytes =
+    fs::read(crx_path).map_err(|error| anyhow!("read {} failed: {error}", crx_path.display()))?;
+  let parsed = parse_crx(&bytes, AcceptedCrx::Crx2OrCrx3)?;
+  let crx_id = extension_id_from_public_key(&parsed.public_key);
+  if crx_id != config.line_extension_id {
+    return Err(anyhow!(
+      "crx id {crx_id} does not match {}",
+      config.line_extension_id
+    ));
+  }
+  let extensions_root = extensions_root(app)?;
+  fs::create_dir_all(&extensions_root)?;
+  fs::write(pending_crx_path(&extensions_root), &bytes)?;
+  info!(
+    "[update] queued local crx {} sha256={}",
+    crx_path.display(),
+    sha256_hex(&bytes)
+  );
+  Ok(())
+}
+
+fn install_pending_crx(
+  extensions_root: &Path,
+  line_dir: &Path,
+  check: &InstallCheck,
+) -> Result<Option<InstallRecord>> {
+  let pending = pending_crx_path(extensions_root);
+  if !pending.is_file() {
+    return Ok(None);
+  }
+  let bytes = fs::read(&pending);
+  // Drop the queued file up front so a broken CRX can't fail every launch.
+  let _ = fs::remove_file(&pending);
+  let bytes = bytes?;
+  install_line_crx(
+    extensions_root,
+    line_dir,
+    &bytes,
+    AcceptedCrx::Crx2OrCrx3,
+    check,
+    "local",
+    Replaced::Archive,
+  )
+  .map(Some)
+}
+
+/// Parses a CRX, stages and validates it next to `line_dir`, then swaps it in.
+/// `line_dir` is left untouched unless the staged copy is complete.
+fn install_line_crx(
+  extensions_root: &Path,
+  line_dir: &Path,
+  crx_bytes: &[u8],
+  accepted: AcceptedCrx,
+  check: &InstallCheck,
+  source: &str,
+  replaced: Replaced,
+) -> Result<InstallRecord> {
+  let crx_sha256 = sha256_hex(crx_bytes);
+  let (staging_dir, unpatched_clear_calls) =
+    stage_line_crx(extensions_root, crx_bytes, accepted, check)?;
+  if let Err(error) = confirm_new_permissions(extensions_root, &staging_dir, line_dir, check) {
+    let _ = fs::remove_dir_all(&staging_dir);
+    return Err(error);
+  }
+  swap_in_staged(extensions_root, &staging_dir, line_dir, replaced)?;
+  if let Err(error) = fs::write(extensions_root.join(LINE_CACHED_CRX), crx_bytes) {
+    warn!("[update] cache crx failed: {error}");
+  }
+
+  let installed_version = read_manifest_version(line_dir);
+  if let Some(version) = installed_version.as_deref() {
+    info!("[update] installed extension v{version} sha256={crx_sha256} ({source})");
+  } else {
+    info!("[update] installed extension sha256={crx_sha256} ({source})");
+  }
+  let record = InstallRecord {
+    version: installed_version,
+    crx_sha256: Some(crx_sha256),
+    installed_at: unix_now(),
+    source: Some(source.to_string()),
+    unpatched_clear_calls,
+    ..InstallRecord::default()
+  };
+  if let Err(error) = write_install_record(extensions_root, &record) {
+    warn!("[update] install record write failed: {error:#}");
+  }
+  Ok(record)
+}
+
+/// Returns the staging directory and any session-clearing calls left in it.
+fn stage_line_crx(
+  extensions_root: &Path,
+  crx_bytes: &[u8],
+  accepted: AcceptedCrx,
+  check: &InstallCheck,
+) -> Result<(PathBuf, Vec<String>)> {
+  let staging_dir = extensions_root.join(LINE_STAGING_DIR);
+  unpack_crx(
+    crx_bytes,
+    accepted,
+    check.extension_id,
+    &staging_dir,
+    &check.extract_limits,
+  )?;
+  match stage_extension(extensions_root, &staging_dir, check) {
+    Ok(unpatched_clear_calls) => Ok((staging_dir, unpatched_clear_calls)),
+    Err(error) => {
+      let _ = fs::remove_dir_all(&staging_dir);
+      Err(error)
+    }
+  }
+}
+
+/// Stages a CRX as `line.ready` for `apply_ready_update` at the next launch.
+fn stage_ready_crx(
+  extensions_root: &Path,
+  crx_bytes: &[u8],
+  crx_sha256: &str,
+  accepted: AcceptedCrx,
+  check: &InstallCheck,
+  source: &str,
+) -> Result<InstallRecord> {
+  let (staging_dir, unpatched_clear_calls) =
+    stage_line_crx(extensions_root, crx_bytes, accepted, check)?;
+  discard_ready_update(extensions_root);
+  let ready_dir = extensions_root.join(LINE_READY_DIR);
+  if let Err(error) = fs::rename(&staging_dir, &ready_dir) {
+    let _ = fs::remove_dir_all(&staging_dir);
+    return Err(error.into());
+  }
+
+  let record = InstallRecord {
+    version: read_manifest_version(&ready_dir),
+    crx_sha256: Some(crx_sha256.to_string()),
+    installed_at: unix_now(),
+    source: Some(source.to_string()),
+    unpatched_clear_calls,
+    ..InstallRecord::default()
+  };
+  let payload = serde_json::to_string_pretty(&record)?;
+  fs::write(extensions_root.join(LINE_READY_RECORD), payload)?;
+  if let Err(error) = fs::write(extensions_root.join(LINE_READY_CRX), crx_bytes) {
+    warn!("[update] cache crx failed: {error}");
+  }
+  info!(
+    "[update] staged extension v{} sha256={crx_sha256} for next launch ({source})",
+    record.version.as_deref().unwrap_or("-")
+  );
+  Ok(record)
+}
+
+/// Swaps a background-staged update into `line_dir` before WebView2 loads it.
+fn apply_ready_update(extensions_root: &Path, line_dir: &Path, check: &InstallCheck) -> Result<()> {
+  let ready_dir = extensions_root.join(LINE_READY_DIR);
+  if !is_extension_dir(&ready_dir) {
+    discard_ready_update(extensions_root);
+    return Ok(());
+  }
+  let record_path = extensions_root.join(LINE_READY_RECORD);
+  let record = fs::read_to_string(&record_path)
+    .ok()
+    .and_then(|raw| serde_json::from_str::<InstallRecord>(&raw).ok())
+    .unwrap_or_else(|| InstallRecord {
+      version: read_manifest_version(&ready_dir),
+      installed_at: unix_now(),
+      ..InstallRecord::default()
+    });
+
+  if let Err(error) = confirm_new_permissions(extensions_root, &ready_dir, line_dir, check) {
+    discard_ready_update(extensions_root);
+    if error.is::<PermissionsDeclined>() {
+      info!("[update] {error}");
+      return Ok(());
+    }
+    return Err(error);
+  }
+  if let Err(error) = swap_in_staged(extensions_root, &ready_dir, line_dir, Replaced::Archive) {
+    discard_ready_update(extensions_root);
+    return Err(error);
+  }
+  let _ = fs::remove_file(&record_path);
+  let _ = fs::rename(
+    extensions_root.join(LINE_READY_CRX),
+    extensions_root.join(LINE_CACHED_CRX),
+  );
+  if let Err(error) = write_install_record(extensions_root, &record) {
+    warn!("[update] install record write failed: {error:#}");
+  }
+  info!(
+    "[update] applied staged extension v{}",
+    record.version.as_deref().unwrap_or("-")
+  );
+  Ok(())
+}
+
+/// The user kept the installed LINE extension instead of one asking for more permissions.
+#[derive(Debug, thiserror::Error)]
+#[error("kept the installed extension; v{version} requests new permissions")]
+struct PermissionsDeclined {
+  version: String,
+}
+
+enum PermissionDecision {
+  Apply,
+  KeepOld,
+  AlwaysAllow,
+}
+
+/// Diffs the permissions of `staged_dir` against the installed `line_dir` and,
+/// if any were added, asks whether to apply the new version.
+fn confirm_new_permissions(
+  extensions_root: &Path,
+  staged_dir: &Path,
+  line_dir: &Path,
+  check: &InstallCheck,
+) -> Result<()> {
+  let Some(app) = check.permission_prompt else {
+    return Ok(());
+  };
+  if !is_extension_dir(line_dir) {
+    return Ok(());
+  }
+  let installed = ExtensionManifest::load(line_dir)?;
+  let staged = ExtensionManifest::load(staged_dir)?;
+  let added = staged.added_permissions(&installed);
+  if added.is_empty() {
+    return Ok(());
+  }
+  info!(
+    "[update] v{} adds permissions={:?} host_permissions={:?}",
+    staged.version, added.permissions, added.host_permissions
+  );
+
+  match ask_permission_decision(app, &installed.version, &staged.version, &added) {
+    PermissionDecision::Apply => Ok(()),
+    PermissionDecision::AlwaysAllow => {
+      let mut settings = load_settings(app).unwrap_or_default();
+      settings.always_allow_line_permissions = true;
+      if let Err(error) = save_settings(app, &settings) {
+        warn!("[update] settings save failed: {error:#}");
+      }
+      Ok(())
+    }
+    PermissionDecision::KeepOld => {
+      // Installs from before install records existed get one here, so the
+      // choice is remembered for them too.
+      let mut record = read_install_record(extensions_root).unwrap_or_else(|| InstallRecord {
+        version: Some(installed.version.clone()),
+        ..InstallRecord::default()
+      });
+      record.declined_version = Some(staged.version.clone());
+      if let Err(error) = write_install_record(extensions_root, &record) {
+        warn!("[update] install record write failed: {error:#}");
       }
+      Err(
+        PermissionsDeclined {
+          version: staged.version,
+        }
+        .into(),
+      )
+    }
+  }
+}
+
+fn ask_permission_decision(
+  app: &tauri::AppHandle,
+  installed_version: &str,
+  version: &str,
+  added: &PermissionDiff,
+) -> PermissionDecision {
+  const APPLY: &str = "適用する";
+  const ALWAYS_ALLOW: &str = "常に許可";
+  const KEEP_OLD: &str = "以前のバージョンを使う";
+
+  let mut lines = Vec::new();
+  if !added.permissions.is_empty() {
+    lines.push(format!("権限: {}", added.permissions.join(", ")));
+  }
+  if !added.host_permissions.is_empty() {
+    lines.push(format!("ホスト権限: {}", added.host_permissions.join(", ")));
+  }
+  let message = format!(
+    "LINE 拡張機能 v{version} は v{installed_version} にない権限を要求しています。\n\n{}\n\nこのバージョンを適用しますか？",
+    lines.join("\n")
+  );
+  // Closing the dialog counts as the cancel button, so that one keeps the old version.
+  let result = app
+    .dialog()
+    .message(message)
+    .title("拡張機能の権限が追加されます")
+    .kind(MessageDialogKind::Warning)
+    .buttons(MessageDialogButtons::YesNoCancelCustom(
+      APPLY.to_string(),
+      ALWAYS_ALLOW.to_string(),
+      KEEP_OLD.to_string(),
+    ))
+    .blocking_show_with_result();
+  match result {
+    MessageDialogResult::Yes => PermissionDecision::Apply,
+    MessageDialogResult::No => PermissionDecision::AlwaysAllow,
+    MessageDialogResult::Custom(label) if label == APPLY => PermissionDecision::Apply,
+    MessageDialogResult::Custom(label) if label == ALWAYS_ALLOW => PermissionDecision::AlwaysAllow,
+    _ => PermissionDecision::KeepOld,
+  }
+}
+
+fn discard_ready_update(extensions_root: &Path) {
+  let ready_dir = extensions_root.join(LINE_READY_DIR);
+  if ready_dir.exists() {
+    let _ = fs::remove_dir_all(&ready_dir);
+  }
+  let _ = fs::remove_file(extensions_root.join(LINE_READY_RECORD));
+  let _ = fs::remove_file(extensions_root.join(LINE_READY_CRX));
+}
+
+fn stage_extension(
+  extensions_root: &Path,
+  staging_dir: &Path,
+  check: &InstallCheck,
+) -> Result<Vec<String>> {
+  ExtensionManifest::load(staging_dir)?.validate()?;
+
+  apply_line_patches(extensions_root, staging_dir)?;
+  // After patching, so a rule that rewrites manifest.json cannot change the ID.
+  verify_manifest_key(staging_dir, check.extension_id)?;
+  record_file_hashes(staging_dir)?;
+
+  let unpatched_clear_calls = find_session_clear_calls(staging_dir)?;
+  if !unpatched_clear_calls.is_empty() {
+    for call in &unpatched_clear_calls {
+      warn!("[patch] session clearing call left unpatched: {call}");
+    }
+    if check.refuse_unpatched_clear {
+      return Err(UnpatchedSessionClear(unpatched_clear_calls).into());
     }
   }
+  Ok(unpatched_clear_calls)
+}
 
-  if crx_bytes.is_none() {
-    info!("[update] download {}", update_url);
-    match download_crx_with_retry(&update_url) {
-      Ok(buffer) => {
-        crx_bytes = Some(buffer);
+/// Replaces `line_dir` with `staging_dir` via two renames, restoring the
+/// previous directory if the second rename fails.
+pub(crate) fn swap_in_staged(
+  extensions_root: &Path,
+  staging_dir: &Path,
+  line_dir: &Path,
+  replaced: Replaced,
+) -> Result<()> {
+  let previous_dir = extensions_root.join(LINE_PREVIOUS_DIR);
+  if previous_dir.exists() {
+    fs::remove_dir_all(&previous_dir)?;
+  }
+  let had_previous = line_dir.exists();
+  if had_previous {
+    fs::rename(line_dir, &previous_dir)?;
+  }
+  if let Err(error) = fs::rename(staging_dir, line_dir) {
+    if had_previous {
+      let _ = fs::rename(&previous_dir, line_dir);
+    }
+    return Err(anyhow!("swap staged extension failed: {error}"));
+  }
+  if had_previous {
+    match replaced {
+      Replaced::Archive => {
+        if let Err(error) = archive_line_dir(extensions_root, &previous_dir) {
+          warn!("[update] archive previous extension failed: {error:#}");
+        }
       }
-      Err(error) => {
-        warn!("[update] download failed: {error:#}");
-        update_failed = true;
+      Replaced::Discard => {
+        if let Err(error) = fs::remove_dir_all(&previous_dir) {
+          warn!("[update] remove modified extension failed: {error}");
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Checks `line_dir` against the file hashes recorded at install. A modified
+/// install, or one whose hashes are gone, is reinstalled from `line.crx` if
+/// that is the CRX it came from, or else from `sources` (`None` while a
+/// version is pinned, since sources offer only the newest). The modified
+/// files are deleted rather than archived; if no reinstall works they are
+/// kept as they are.
+fn verify_line_extension(
+  extensions_root: &Path,
+  line_dir: &Path,
+  check: &InstallCheck,
+  sources: Option<(&AppConfig, &ProgressReporter)>,
+) {
+  if !is_extension_dir(line_dir) {
+    return;
+  }
+  let record = read_install_record(extensions_root);
+  match verify_file_hashes(line_dir) {
+    Ok(Some(mismatches)) if mismatches.is_empty() => return,
+    Ok(Some(mismatches)) => {
+      for mismatch in &mismatches {
+        warn!("[integrity] {mismatch}");
       }
+      warn!(
+        "[integrity] {} files differ from the install, reinstalling",
+        mismatches.len()
+      );
+    }
+    // Only installs that predate the install record have no hashes.
+    Ok(None) if record.is_none() => {
+      debug!("[integrity] no file hashes recorded yet");
+      return;
+    }
+    Ok(None) => warn!("[integrity] file hashes missing, reinstalling"),
+    Err(error) => {
+      warn!("[integrity] verify failed: {error:#}");
+      return;
     }
   }
 
-  if update_failed {
-    if has_existing {
-      info!("[update] use local extension (update failed)");
-      let _ = disable_cache_clear(&line_dir);
-      let _ = disable_legacy_clear(&line_dir);
-      return Ok(ExtensionSetup {
+  let cached = fs::read(extensions_root.join(LINE_CACHED_CRX))
+    .ok()
+    .filter(|bytes| {
+      record
+        .as_ref()
+        .and_then(|record| record.crx_sha256.as_deref())
+        == Some(sha256_hex(bytes).as_str())
+    });
+  let reinstall = match (cached, sources) {
+    // `line.crx` was accepted when it was first installed.
+    (Some(bytes), _) => install_line_crx(
+      extensions_root,
+      line_dir,
+      &bytes,
+      AcceptedCrx::Crx2OrCrx3,
+      check,
+      "cache",
+      Replaced::Discard,
+    )
+    .map(|_| ()),
+    (None, Some((config, progress))) => {
+      info!("[integrity] no cached crx for this install, reinstalling from update sources");
+      update_from_sources(
+        config,
+        check,
+        extensions_root,
         line_dir,
-        user_dir,
-        updated: false,
-        update_failed: true,
-      });
+        None,
+        progress,
+        InstallTarget::Repair,
+      )
+      .and_then(|(source, outcome)| match outcome {
+        SourceOutcome::Installed => Ok(()),
+        SourceOutcome::UpToDate => Err(anyhow!("{source} offered no version to install")),
+      })
     }
-    return Err(anyhow!("update download failed after retries"));
+    (None, None) => Err(anyhow!("no cached crx for this install")),
+  };
+  match reinstall {
+    Ok(()) => {
+      let reinstalled = read_install_record(extensions_root);
+      if let (Some(record), Some(mut reinstalled)) = (record, reinstalled) {
+        reinstalled.declined_version = record.declined_version;
+        reinstalled.refused_version = record.refused_version;
+        if let Err(error) = write_install_record(extensions_root, &reinstalled) {
+          warn!("[update] install record write failed: {error:#}");
+        }
+      }
+    }
+    Err(error) => warn!("[integrity] reinstall failed, keeping the modified install: {error:#}"),
   }
+}
 
-  let crx_bytes = crx_bytes.ok_or_else(|| anyhow!("crx bytes missing"))?;
-  let parsed = parse_crx3(&crx_bytes)?;
-  ensure_clean_dir(&line_dir)?;
-  extract_zip(&parsed.zip_bytes, &line_dir)?;
-  inject_manifest_key(&line_dir, &parsed.public_key)?;
-  let _ = disable_cache_clear(&line_dir);
-  let _ = disable_legacy_clear(&line_dir);
-  if let Some(version) = read_manifest_version(&line_dir) {
-    info!("[update] installed extension v{} (network)", version);
-  } else {
-    info!("[update] installed extension (network)");
+/// Restores the previous install if a crash happened between the two renames
+/// of `swap_in_staged`, and drops any half-written staging directory.
+fn recover_interrupted_install(extensions_root: &Path, line_dir: &Path) {
+  let previous_dir = extensions_root.join(LINE_PREVIOUS_DIR);
+  if !is_extension_dir(line_dir) && is_extension_dir(&previous_dir) {
+    warn!("[update] restoring previous extension after interrupted install");
+    let _ = fs::remove_dir_all(line_dir);
+    if let Err(error) = fs::rename(&previous_dir, line_dir) {
+      warn!("[update] restore previous extension failed: {error:#}");
+    }
+  } else if previous_dir.exists() {
+    if let Err(error) = archive_line_dir(extensions_root, &previous_dir) {
+      warn!("[update] archive previous extension failed: {error:#}");
+    }
   }
+  let staging_dir = extensions_root.join(LINE_STAGING_DIR);
+  if staging_dir.exists() {
+    let _ = fs::remove_dir_all(&staging_dir);
+  }
+}
 
-  Ok(ExtensionSetup {
+fn fallback_to_local(
+  extensions_root: &Path,
+  line_dir: PathBuf,
+  user_dir: PathBuf,
+  has_existing: bool,
+) -> Result<ExtensionSetup> {
+  if !has_existing {
+    return Err(anyhow!("update download failed after retries"));
+  }
+  log_local_extension(
+    extensions_root,
+    read_manifest_version(&line_dir).as_deref(),
+    "update failed",
+  );
+  Ok(use_local(extensions_root, line_dir, user_dir, true))
+}
+
+fn use_local(
+  extensions_root: &Path,
+  line_dir: PathBuf,
+  user_dir: PathBuf,
+  update_failed: bool,
+) -> ExtensionSetup {
+  if let Err(error) = patch_line_extension(extensions_root, &line_dir) {
+    warn!("[patch] failed: {error:#}");
+  }
+  ExtensionSetup {
     line_dir,
     user_dir,
-    updated,
-    update_failed: false,
-  })
+    updated: false,
+    update_failed,
+    unpatched_clear_calls: Vec::new(),
+  }
 }
 
 #[cfg(target_os = "windows")]
@@ -205,91 +1202,178 @@ fn ensure_extension_enabled(extension: &ICoreWebView2BrowserExtension) -> Result
   Ok(())
 }
 
-fn is_extension_dir(path: &Path) -> bool {
-  path.join("manifest.json").is_file()
-}
-
-fn read_manifest_version(path: &Path) -> Option<String> {
-  let manifest_path = path.join("manifest.json");
-  let raw = fs::read_to_string(&manifest_path).ok()?;
-  let value: Value = serde_json::from_str(&raw).ok()?;
-  value
-    .get("version")
-    .and_then(|v| v.as_str())
-    .map(|v| v.to_string())
-}
-
-fn disable_cache_clear(line_dir: &Path) -> Result<()> {
-  let cache_path = line_dir.join("cache.js");
-  if !cache_path.is_file() {
-    return Ok(());
+#[cfg(target_os = "windows")]
+fn remove_unlisted_extensions(profile: &ICoreWebView2Profile7, keep_ids: &[String]) -> Result<()> {
+  let (tx, rx) = mpsc::channel();
+  let handler =
+    ProfileGetBrowserExtensionsCompletedHandler::create(Box::new(move |result, extensions| {
+      let _ = tx.send((result, extensions));
+      Ok(())
+    }));
+  unsafe {
+    profile.GetBrowserExtensions(&handler)?;
   }
-  let raw = fs::read_to_string(&cache_path)?;
-  if raw.contains("caches.delete(CACHE_NAME)") {
-    let updated = raw.replace("caches.delete(CACHE_NAME)", "Promise.resolve()");
-    fs::write(&cache_path, updated)?;
+  let (result, extensions) =
+    wait_with_pump(rx).map_err(|error| anyhow!("extension list callback error: {error:?}"))?;
+  if let Err(error) = result {
+    return Err(anyhow!("list extensions failed: {error:?}"));
   }
-  Ok(())
-}
-
-fn disable_legacy_clear(line_dir: &Path) -> Result<()> {
-  let background_path = line_dir.join("background.js");
-  if !background_path.is_file() {
+  let Some(extensions) = extensions else {
     return Ok(());
+  };
+
+  let mut count = 0;
+  unsafe {
+    extensions.Count(&mut count)?;
   }
-  let raw = fs::read_to_string(&background_path)?;
-  let mut updated = raw.clone();
-  let mut changed = false;
-  if raw.contains("chrome.storage.local.clear()") {
-    updated = updated.replace("chrome.storage.local.clear()", "Promise.resolve()");
-    changed = true;
-  }
-  if raw.contains("indexedDB.databases()") {
-    updated = updated.replace("indexedDB.databases()", "Promise.resolve([])");
-    changed = true;
+  let mut stale = Vec::new();
+  for idx in 0..count {
+    let extension = unsafe { extensions.GetValueAtIndex(idx)? };
+    let id = browser_extension_id(&extension)?;
+    if !keep_ids.contains(&id) {
+      stale.push((id, extension));
+    }
   }
-  if changed {
-    fs::write(&background_path, updated)?;
+
+  for (id, extension) in stale {
+    let (tx, rx) = mpsc::channel();
+    let handler = BrowserExtensionRemoveCompletedHandler::create(Box::new(move |result| {
+      let _ = tx.send(result);
+      Ok(())
+    }));
+    unsafe {
+      extension.Remove(&handler)?;
+    }
+    let result =
+      wait_with_pump(rx).map_err(|error| anyhow!("extension remove callback error: {error:?}"))?;
+    match result {
+      Ok(()) => info!("[ext] removed {id} from profile"),
+      Err(error) => warn!("[ext] remove {id} from profile failed: {error:?}"),
+    }
   }
   Ok(())
 }
 
-fn download_crx_with_retry(url: &str) -> Result<Vec<u8>> {
-  const RETRIES: usize = 5;
-  const WAIT_SECS: u64 = 30;
-  for attempt in 1..=RETRIES {
-    match download_crx(url) {
-      Ok(bytes) => return Ok(bytes),
-      Err(error) => {
-        if attempt >= RETRIES {
-          return Err(anyhow!("download failed after retries: {error:#}"));
-        }
-        warn!(
-          "[update] download failed (attempt {attempt}/{RETRIES}): {error:#}; retrying in {WAIT_SECS}s"
-        );
-        std::thread::sleep(std::time::Duration::from_secs(WAIT_SECS));
-      }
-    }
-  }
-  Err(anyhow!("download failed after retries"))
+/// Identifies the installed LINE extension build; stored next to `extensions/line`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct InstallRecord {
+  pub(crate) version: Option<String>,
+  pub(crate) crx_sha256: Option<String>,
+  pub(crate) installed_at: u64,
+  pub(crate) source: Option<String>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub(crate) unpatched_clear_calls: Vec<String>,
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  pub(crate) unpatched_clear_warned: bool,
+  /// Newer version the user kept out because it requested new permissions.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub(crate) declined_version: Option<String>,
+  /// Newer version refused because session clearing calls survived patching.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub(crate) refused_version: Option<String>,
 }
 
-fn collect_user_extension_dirs(user_dir: &Path) -> Result<Vec<PathBuf>> {
-  if is_extension_dir(user_dir) {
-    return Ok(vec![user_dir.to_path_buf()]);
-  }
+fn install_record_path(extensions_root: &Path) -> PathBuf {
+  extensions_root.join("line.install.json")
+}
 
-  let mut dirs = Vec::new();
-  if user_dir.is_dir() {
-    for entry in fs::read_dir(user_dir)? {
-      let path = entry?.path();
-      if path.is_dir() && is_extension_dir(&path) {
-        dirs.push(path);
-      }
-    }
+pub(crate) fn read_install_record(extensions_root: &Path) -> Option<InstallRecord> {
+  let raw = fs::read_to_string(install_record_path(extensions_root)).ok()?;
+  serde_json::from_str(&raw).ok()
+}
+
+pub(crate) fn write_install_record(extensions_root: &Path, record: &InstallRecord) -> Result<()> {
+  let payload = serde_json::to_string_pretty(record)?;
+  fs::write(install_record_path(extensions_root), payload)?;
+  Ok(())
+}
+
+fn log_local_extension(extensions_root: &Path, version: Option<&str>, reason: &str) {
+  let sha256 = read_install_record(extensions_root)
+    .and_then(|record| record.crx_sha256)
+    .unwrap_or_else(|| "unknown".to_string());
+  info!(
+    "[update] use local extension v{} sha256={sha256} ({reason})",
+    version.unwrap_or("-")
+  );
+}
+
+pub(crate) fn unix_now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_secs()
+}
+
+pub(crate) fn is_extension_dir(path: &Path) -> bool {
+  path.join("manifest.json").is_file()
+}
+
+pub(crate) fn read_manifest_version(path: &Path) -> Option<String> {
+  ExtensionManifest::read_version(path).filter(|version| !version.is_empty())
+}
+
+/// Patches the installed `dir` at launch. Its file hashes are refreshed for
+/// the patched files only if they matched before, so a modified install is
+/// never recorded as the one that was installed.
+fn patch_line_extension(extensions_root: &Path, dir: &Path) -> Result<PatchReport> {
+  let verified = matches!(verify_file_hashes(dir), Ok(Some(mismatches)) if mismatches.is_empty());
+  let report = apply_line_patches(extensions_root, dir)?;
+  if report.changed_files() && verified {
+    record_file_hashes(dir)?;
   }
+  Ok(report)
+}
+
+fn apply_line_patches(extensions_root: &Path, dir: &Path) -> Result<PatchReport> {
+  let rules = load_patch_rules(extensions_root);
+  let report = apply_patches(dir, &rules)?;
+  report.log(dir);
+  Ok(report)
+}
 
-  Ok(dirs)
+/// Retries `action` with backoff, showing each wait on the loading screen,
+/// where the user can retry now, cancel, or (when `can_use_cached`) give up
+/// and use the local copy.
+pub(crate) fn retry_with_wait<T>(
+  label: &str,
+  progress: &ProgressReporter,
+  can_use_cached: bool,
+  action: impl FnMut() -> Result<T>,
+) -> Result<T> {
+  let on_retry = |wait: &RetryWait| {
+    warn!(
+      "[update] {label} failed (attempt {}/{}): {:#}; retrying in {}ms",
+      wait.attempt,
+      wait.max_attempts,
+      wait.error,
+      wait.delay.as_millis()
+    );
+    progress.emit(ExtensionProgress::Retrying {
+      label: label.to_string(),
+      attempt: wait.attempt,
+      max_attempts: wait.max_attempts,
+      next_retry_at: unix_now_millis() + wait.delay.as_millis() as u64,
+      error: format!("{:#}", wait.error),
+      can_use_cached,
+    });
+  };
+  retry_with_backoff(
+    &RetryPolicy::default(),
+    progress,
+    can_use_cached,
+    ends_update,
+    on_retry,
+    action,
+  )
+  .map_err(|error| {
+    if ends_update(&error) {
+      error
+    } else {
+      error.context(format!("{label} failed"))
+    }
+  })
 }
 
 #[cfg(target_os = "windows")]
@@ -297,6 +1381,7 @@ pub(crate) fn install_extensions_and_open(
   webview: PlatformWebview,
   line_dir: PathBuf,
   user_dir: PathBuf,
+  disabled_user_extensions: Vec<String>,
   entry_path: String,
 ) -> Result<()> {
   let controller = webview.controller();
@@ -318,9 +1403,16 @@ pub(crate) fn install_extensions_and_open(
   ensure_extension_enabled(&line_extension)?;
   let line_id = browser_extension_id(&line_extension)?;
 
-  for user_extension in collect_user_extension_dirs(&user_dir)? {
+  let mut loaded_ids = vec![line_id.clone()];
+  for user_extension in collect_user_extension_dirs(&user_dir, &disabled_user_extensions)? {
     let extension = add_browser_extension(&profile, &user_extension)?;
     ensure_extension_enabled(&extension)?;
+    loaded_ids.push(browser_extension_id(&extension)?);
+  }
+  // The profile keeps extensions added in earlier sessions; drop the ones
+  // that were disabled or removed since.
+  if let Err(error) = remove_unlisted_extensions(&profile, &loaded_ids) {
+    warn!("[ext] stale extension cleanup failed: {error:#}");
   }
 
   let page_url = format!("chrome-extension://{line_id}{entry_path}");
@@ -585,3 +1677,80 @@ fn persist_all_session_cookies(core: &ICoreWebView2, tag: &str) -> Result<()> {
   debug!("[cookie] {tag} all persisted {updated}/{session_total} session cookies");
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::crx::{pack_crx_dir, CrxSigningKey};
+
+  #[test]
+  fn seeds_sort_newest_version_first() {
+    let mut seeds = [
+      "line-9.crx",
+      "line-10.crx",
+      "line-3.7.1.crx",
+      "line-10.0.2.crx",
+    ]
+    .map(PathBuf::from)
+    .to_vec();
+    sort_newest_seed_first(&mut seeds);
+    assert_eq!(
+      seeds,
+      [
+        "line-10.0.2.crx",
+        "line-10.crx",
+        "line-9.crx",
+        "line-3.7.1.crx"
+      ]
+      .map(PathBuf::from)
+    );
+  }
+
+  #[test]
+  fn reinstalls_modified_files_without_archiving_them() {
+    let source = tempfile::tempdir().unwrap();
+    fs::write(
+      source.path().join("manifest.json"),
+      r#"{"name":"LINE","version":"3.7.1","manifest_version":3}"#,
+    )
+    .unwrap();
+    fs::write(source.path().join("main.js"), "main()").unwrap();
+    let key = CrxSigningKey::Ecdsa(p256::ecdsa::SigningKey::random(&mut rand::thread_rng()));
+    let crx = pack_crx_dir(source.path(), &key).unwrap();
+    let extension_id = extension_id_from_public_key(&key.public_key_der().unwrap());
+    let check = InstallCheck {
+      extension_id: &extension_id,
+      refuse_unpatched_clear: false,
+      permission_prompt: None,
+      extract_limits: ExtractLimits::default(),
+    };
+
+    let root = tempfile::tempdir().unwrap();
+    let line_dir = line_dir(root.path());
+    install_line_crx(
+      root.path(),
+      &line_dir,
+      &crx,
+      AcceptedCrx::Crx3,
+      &check,
+      "test",
+      Replaced::Archive,
+    )
+    .unwrap();
+    let archived = root.path().join("versions").join("3.7.1");
+    fs::create_dir_all(&archived).unwrap();
+    fs::write(archived.join("main.js"), "main()").unwrap();
+
+    fs::write(line_dir.join("main.js"), "evil()").unwrap();
+    verify_line_extension(root.path(), &line_dir, &check, None);
+    assert_eq!(
+      fs::read_to_string(line_dir.join("main.js")).unwrap(),
+      "main()"
+    );
+    assert_eq!(
+      fs::read_to_string(archived.join("main.js")).unwrap(),
+      "main()"
+    );
+    assert!(!root.path().join(LINE_PREVIOUS_DIR).exists());
+  }
+}
@@ -0,0 +1,156 @@
+use crate::settings::NetworkSettings;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use rustls::RootCertStore;
+use std::env;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use ureq::{Agent, AgentBuilder, Proxy};
+use url::Url;
+
+const PROXY_ENV_VARS: [&str; 4] = ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"];
+
+static HTTP_AGENT: RwLock<Option<Agent>> = RwLock::new(None);
+
+/// Builds the shared HTTP agent from settings. Called once at startup and
+/// again whenever the network settings change.
+fn configure_http_client(settings: &NetworkSettings) -> Result<()> {
+  let agent = build_agent(settings)?;
+  if let Ok(mut slot) = HTTP_AGENT.write() {
+    *slot = Some(agent);
+  }
+  Ok(())
+}
+
+/// Applies `settings` to the shared client, at startup and when they are
+/// saved. If the extra root CAs cannot be loaded, the proxy and timeouts are
+/// still applied so requests keep going through the proxy. The original error
+/// is returned for the caller to show.
+pub(crate) fn apply_network_settings(settings: &NetworkSettings) -> Result<()> {
+  let error = match configure_http_client(settings) {
+    Ok(()) => return Ok(()),
+    Err(error) => error,
+  };
+  let without_root_ca = NetworkSettings {
+    root_ca_file: None,
+    ..settings.clone()
+  };
+  match build_agent(&without_root_ca) {
+    Ok(agent) => {
+      warn!("[http] using network settings without extra root CAs");
+      if let Ok(mut slot) = HTTP_AGENT.write() {
+        *slot = Some(agent);
+      }
+    }
+    Err(fallback_error) => warn!("[http] fallback client failed: {fallback_error:#}"),
+  }
+  Err(error)
+}
+
+/// Returns the shared HTTP agent. Falls back to defaults (still honouring
+/// `HTTPS_PROXY`) if `configure_http_client` has not succeeded.
+pub(crate) fn http_agent() -> Agent {
+  if let Ok(slot) = HTTP_AGENT.read() {
+    if let Some(agent) = slot.as_ref() {
+      return agent.clone();
+    }
+  }
+  let agent = match build_agent(&NetworkSettings::default()) {
+    Ok(agent) => agent,
+    Err(error) => {
+      warn!("[http] default client failed: {error:#}");
+      AgentBuilder::new().build()
+    }
+  };
+  if let Ok(mut slot) = HTTP_AGENT.write() {
+    slot.get_or_insert_with(|| agent.clone());
+  }
+  agent
+}
+
+fn build_agent(settings: &NetworkSettings) -> Result<Agent> {
+  let mut builder = AgentBuilder::new()
+    .timeout_connect(Duration::from_secs(settings.connect_timeout_secs.max(1)))
+    .timeout_read(Duration::from_secs(settings.read_timeout_secs.max(1)))
+    .timeout_write(Duration::from_secs(settings.read_timeout_secs.max(1)));
+
+  if let Some(proxy_url) = resolve_proxy_url(settings) {
+    let proxy = Proxy::new(&proxy_url).map_err(|error| anyhow!("invalid proxy: {error}"))?;
+    info!("[http] proxy {}", redact_proxy_url(&proxy_url));
+    builder = builder.proxy(proxy);
+  }
+
+  if let Some(path) = settings
+    .root_ca_file
+    .as_deref()
+    .filter(|path| !path.trim().is_empty())
+  {
+    let tls_config = build_tls_config(Path::new(path))?;
+    builder = builder.tls_config(Arc::new(tls_config));
+  }
+
+  Ok(builder.build())
+}
+
+fn resolve_proxy_url(settings: &NetworkSettings) -> Option<String> {
+  if let Some(proxy_url) = settings
+    .proxy_url
+    .as_deref()
+    .map(str::trim)
+    .filter(|value| !value.is_empty())
+  {
+    return Some(proxy_url.to_string());
+  }
+  PROXY_ENV_VARS
+    .iter()
+    .filter_map(|name| env::var(name).ok())
+    .map(|value| value.trim().to_string())
+    .find(|value| !value.is_empty())
+}
+
+// Proxy URLs may carry credentials; only the scheme and host end up in the log.
+fn redact_proxy_url(proxy_url: &str) -> String {
+  match Url::parse(proxy_url) {
+    Ok(url) => format!(
+      "{}://{}:{}",
+      url.scheme(),
+      url.host_str().unwrap_or("-"),
+      url.port_or_known_default().unwrap_or_default()
+    ),
+    Err(_) => "(unparsed)".to_string(),
+  }
+}
+
+/// Trusts the bundled web PKI roots plus every certificate in `path`,
+/// e.g. the root of a TLS-inspecting corporate proxy.
+fn build_tls_config(path: &Path) -> Result<rustls::ClientConfig> {
+  let mut roots = RootCertStore::empty();
+  roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+  let certs = CertificateDer::pem_file_iter(path)
+    .map_err(|error| anyhow!("read {} failed: {error}", path.display()))?
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|error| anyhow!("parse {} failed: {error}", path.display()))?;
+  if certs.is_empty() {
+    return Err(anyhow!("no certificates in {}", path.display()));
+  }
+  let (added, ignored) = roots.add_parsable_certificates(certs);
+  if added == 0 {
+    return Err(anyhow!("no usable certificates in {}", path.display()));
+  }
+  info!(
+    "[http] extra root CAs added={added} ignored={ignored} file={}",
+    path.display()
+  );
+
+  let provider = Arc::new(rustls::crypto::ring::default_provider());
+  let config = rustls::ClientConfig::builder_with_provider(provider)
+    .with_safe_default_protocol_versions()
+    .map_err(|error| anyhow!("tls config failed: {error}"))?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+  Ok(config)
+}
@@ -0,0 +1,94 @@
+use crate::crx::sha256_hex;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// SHA-256 of every other file in the extension folder, keyed by the path
+/// relative to it with `/` separators. Written when an install is staged, after
+/// patching, so it covers `refined-line.patches.json` too.
+const FILE_HASHES_FILE: &str = "refined-line.files.json";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum FileMismatch {
+  Missing(String),
+  Changed(String),
+  Unexpected(String),
+}
+
+impl fmt::Display for FileMismatch {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      FileMismatch::Missing(path) => write!(f, "{path}: missing"),
+      FileMismatch::Changed(path) => write!(f, "{path}: changed"),
+      FileMismatch::Unexpected(path) => write!(f, "{path}: not installed"),
+    }
+  }
+}
+
+pub(crate) fn record_file_hashes(dir: &Path) -> Result<()> {
+  let hashes = hash_files(dir)?;
+  fs::write(
+    dir.join(FILE_HASHES_FILE),
+    serde_json::to_string_pretty(&hashes)?,
+  )?;
+  Ok(())
+}
+
+/// Compares `dir` with its recorded hashes. `None` when nothing was recorded.
+pub(crate) fn verify_file_hashes(dir: &Path) -> Result<Option<Vec<FileMismatch>>> {
+  let path = dir.join(FILE_HASHES_FILE);
+  let raw = match fs::read_to_string(&path) {
+    Ok(raw) => raw,
+    Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+    Err(error) => return Err(anyhow!("read {} failed: {error}", path.display())),
+  };
+  let mut expected: BTreeMap<String, String> = serde_json::from_str(&raw)
+    .map_err(|error| anyhow!("parse {} failed: {error}", path.display()))?;
+
+  let mut mismatches = Vec::new();
+  for (relative, hash) in hash_files(dir)? {
+    match expected.remove(&relative) {
+      Some(expected_hash) if expected_hash == hash => {}
+      Some(_) => mismatches.push(FileMismatch::Changed(relative)),
+      None => mismatches.push(FileMismatch::Unexpected(relative)),
+    }
+  }
+  mismatches.extend(expected.into_keys().map(FileMismatch::Missing));
+  Ok(Some(mismatches))
+}
+
+fn hash_files(dir: &Path) -> Result<BTreeMap<String, String>> {
+  let mut files = Vec::new();
+  collect_files(dir, &mut files)?;
+  let mut hashes = BTreeMap::new();
+  for path in files {
+    let relative = path
+      .strip_prefix(dir)?
+      .components()
+      .map(|component| component.as_os_str().to_string_lossy())
+      .collect::<Vec<_>>()
+      .join("/");
+    if relative == FILE_HASHES_FILE {
+      continue;
+    }
+    let bytes =
+      fs::read(&path).map_err(|error| anyhow!("read {} failed: {error}", path.display()))?;
+    hashes.insert(relative, sha256_hex(&bytes));
+  }
+  Ok(hashes)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    let file_type = entry.file_type()?;
+    if file_type.is_dir() {
+      collect_files(&entry.path(), files)?;
+    } else {
+      files.push(entry.path());
+    }
+  }
+  Ok(())
+}
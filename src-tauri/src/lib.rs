@@ -1,20 +1,37 @@
 mod app_menu;
+mod cli;
 mod commands;
 mod config;
 mod content_protection;
 mod crx;
 mod extensions;
+mod http_client;
 mod injections;
+mod integrity;
+mod line_versions;
 mod logger;
+mod manifest;
+mod patches;
 mod paths;
+mod progress;
+mod retry;
 mod settings;
 mod tray;
+mod update2;
+mod update_schedule;
+mod update_sources;
 mod updater;
+mod user_extensions;
 mod windowing;
 
 use app_menu::{build_menu, handle_menu_event, menu_action};
+use cli::{parse_cli_args, run_subcommand};
 use commands::{
-  confirm_reset_profile, get_is_dev, get_is_maximized, get_settings, reset_profile, update_settings,
+  confirm_reset_profile, confirm_rollback_line_extension, confirm_uninstall_user_extension,
+  get_extension_progress, get_is_dev, get_is_maximized, get_line_extension_info, get_settings,
+  get_user_extensions, install_line_extension_crx, install_store_user_extension,
+  list_line_extension_versions, reset_profile, respond_extension_retry, rollback_line_extension,
+  set_user_extension_enabled, uninstall_user_extension, update_settings,
 };
 use config::load_config;
 use content_protection::{
@@ -24,21 +41,29 @@ use content_protection::{
 #[cfg(target_os = "windows")]
 use extensions::install_extensions_and_open;
 use extensions::{
-  log_cookies_snapshot, persist_session_cookies_snapshot, prepare_extensions, ExtensionSetup,
+  log_cookies_snapshot, persist_session_cookies_snapshot, prepare_extensions, queue_local_crx,
+  ExtensionSetup,
 };
+use http_client::apply_network_settings;
 use injections::{inject_hotkeys, inject_scripts, inject_titlebar};
 use log::{debug, error, info, warn};
 use logger::{apply_log_level, build_plugin, resolve_log_level};
 use paths::{profile_dir, profile_reset_marker};
+use progress::ProgressState;
+#[cfg(target_os = "windows")]
+use progress::{ExtensionProgress, ProgressReporter};
 use settings::{load_settings, save_settings};
+use std::sync::Arc;
 use tauri::webview::PageLoadEvent;
 #[cfg(target_os = "windows")]
 use tauri::webview::ScrollBarStyle;
 use tauri::{Manager, WebviewUrl, WebviewWindowBuilder};
 use tauri_plugin_autostart::ManagerExt;
-use tauri_plugin_dialog::DialogExt;
+use tauri_plugin_dialog::{DialogExt, MessageDialogKind};
 use tauri_plugin_opener::OpenerExt;
 use tray::{init_tray_state, is_tray_enabled};
+#[cfg(target_os = "windows")]
+use update_schedule::spawn_extension_update_scheduler;
 use updater::spawn_update_check;
 #[cfg(target_os = "windows")]
 use windowing::{
@@ -48,6 +73,9 @@ use windowing::{next_popup_label, should_open_external};
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
+  if let Some(code) = run_subcommand(std::env::args_os().skip(1)) {
+    std::process::exit(code);
+  }
   tauri::Builder::default()
     .on_page_load(|webview, payload| {
       if payload.event() != PageLoadEvent::Finished {
@@ -88,8 +116,20 @@ pub fn run() {
       update_settings,
       get_is_dev,
       get_is_maximized,
+      get_line_extension_info,
+      get_extension_progress,
+      respond_extension_retry,
       confirm_reset_profile,
       reset_profile,
+      list_line_extension_versions,
+      confirm_rollback_line_extension,
+      rollback_line_extension,
+      install_line_extension_crx,
+      get_user_extensions,
+      install_store_user_extension,
+      confirm_uninstall_user_extension,
+      uninstall_user_extension,
+      set_user_extension_enabled,
       menu_action
     ])
     .on_window_event(|window, event| {
@@ -118,7 +158,17 @@ pub fn run() {
         }
       }
       app.manage(WindowState::new(settings.content_protection));
+      app.manage(Arc::new(ProgressState::default()));
       apply_log_level(resolve_log_level(&settings.log_level));
+      if let Err(error) = apply_network_settings(&settings.network) {
+        error!("[http] client config failed: {error:#}");
+        app_handle
+          .dialog()
+          .message(format!("ネットワーク設定を適用できませんでした。\n{error:#}"))
+          .title("ネットワーク設定")
+          .kind(MessageDialogKind::Warning)
+          .show(|_| {});
+      }
       let config = load_config(&app_handle)?;
       let menu_state = build_menu(&app_handle, &settings)?;
       let profile_dir = profile_dir(&app_handle)?;
@@ -141,6 +191,21 @@ pub fn run() {
       }
       std::fs::create_dir_all(&profile_dir)?;
 
+      let cli = parse_cli_args(std::env::args_os().skip(1));
+      if let Some(crx_path) = cli.install_crx.as_deref() {
+        if let Err(error) = queue_local_crx(&app_handle, crx_path) {
+          error!(
+            "[update] --install-crx {} failed: {error:#}",
+            crx_path.display()
+          );
+          app_handle
+            .dialog()
+            .message(format!("CRX ファイルを読み込めませんでした。\n{error}"))
+            .title("インストール失敗")
+            .show(|_| {});
+        }
+      }
+
       let base_title = "refined-line";
       let conf = app_handle
         .config()
@@ -291,10 +356,14 @@ pub fn run() {
           user_dir,
           updated,
           update_failed,
+          unpatched_clear_calls,
         } = match prepare_extensions(&app_handle_for_update) {
           Ok(result) => result,
           Err(error) => {
             error!("[update] failed: {error:#}");
+            ProgressReporter::new(&app_handle_for_update).emit(ExtensionProgress::Failed {
+              message: format!("{error:#}"),
+            });
             let app_handle = app_handle_for_update.clone();
             let dialog_handle = app_handle.clone();
             let _ = app_handle.run_on_main_thread(move || {
@@ -308,6 +377,9 @@ pub fn run() {
           }
         };
 
+        let disabled_user_extensions = load_settings(&app_handle_for_update)
+          .map(|settings| settings.disabled_user_extensions)
+          .unwrap_or_default();
         let app_handle_for_install = app_handle_for_update.clone();
         let entry_path_for_install = entry_path.clone();
         let handle_for_task = app_handle_for_install.clone();
@@ -318,14 +390,17 @@ pub fn run() {
             warn!("[open] main window not found");
             return;
           };
+          ProgressReporter::new(&handle_for_task).emit(ExtensionProgress::Opening);
           let line_dir_for_install = line_dir.clone();
           let user_dir_for_install = user_dir.clone();
+          let disabled_for_install = disabled_user_extensions.clone();
           let entry_path_for_install = entry_path_for_install.clone();
           if let Err(error) = window.with_webview(move |webview| {
             let result = install_extensions_and_open(
               webview,
               line_dir_for_install.clone(),
               user_dir_for_install.clone(),
+              disabled_for_install.clone(),
               entry_path_for_install.clone(),
             );
             if let Err(error) = result {
@@ -335,6 +410,7 @@ pub fn run() {
           }) {
             error!("[open] with_webview failed: {error:#}");
           }
+          spawn_extension_update_scheduler(&handle_for_task);
 
           let handle_for_cookies = handle_for_task.clone();
           std::thread::spawn(move || {
@@ -363,6 +439,22 @@ pub fn run() {
             }
           });
 
+          if !unpatched_clear_calls.is_empty() {
+            warn!(
+              "[patch] installed extension keeps session clearing calls: {}",
+              unpatched_clear_calls.join(", ")
+            );
+            handle_for_task
+              .dialog()
+              .message(format!(
+                "拡張機能の更新により、ログイン状態を保持するパッチが一部適用できませんでした。ログアウトされる可能性があります。\n\n{}",
+                unpatched_clear_calls.join("\n")
+              ))
+              .title("警告")
+              .kind(MessageDialogKind::Warning)
+              .show(|_| {});
+          }
+
           if update_failed_for_dialog {
             let app_handle = handle_for_task.clone();
             app_handle
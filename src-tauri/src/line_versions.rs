@@ -0,0 +1,165 @@
+use crate::extensions::{
+  is_extension_dir, read_install_record, read_manifest_version, swap_in_staged,
+  write_install_record, InstallRecord, Replaced, LINE_STAGING_DIR,
+};
+use crate::update2::compare_versions;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const LINE_VERSIONS_DIR: &str = "versions";
+const KEEP_LINE_VERSIONS: usize = 3;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LineVersion {
+  pub(crate) version: String,
+  pub(crate) crx_sha256: Option<String>,
+  pub(crate) installed_at: u64,
+  pub(crate) active: bool,
+}
+
+fn versions_dir(extensions_root: &Path) -> PathBuf {
+  extensions_root.join(LINE_VERSIONS_DIR)
+}
+
+fn archived_record_path(extensions_root: &Path, version: &str) -> PathBuf {
+  versions_dir(extensions_root).join(format!("{version}.install.json"))
+}
+
+// Versions come from manifest.json and end up as directory names.
+fn is_valid_version(version: &str) -> bool {
+  !version.is_empty()
+    && version
+      .split('.')
+      .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Moves a replaced LINE extension directory into `extensions/versions/<version>`
+/// together with its install record, then prunes old versions.
+pub(crate) fn archive_line_dir(extensions_root: &Path, dir: &Path) -> Result<()> {
+  let version = match read_manifest_version(dir) {
+    Some(version) if is_valid_version(&version) => version,
+    _ => {
+      fs::remove_dir_all(dir)?;
+      return Ok(());
+    }
+  };
+
+  let versions_dir = versions_dir(extensions_root);
+  fs::create_dir_all(&versions_dir)?;
+  let dest = versions_dir.join(&version);
+  if dest.exists() {
+    fs::remove_dir_all(&dest)?;
+  }
+  fs::rename(dir, &dest)?;
+
+  if let Some(record) = read_install_record(extensions_root) {
+    if record.version.as_deref() == Some(version.as_str()) {
+      let payload = serde_json::to_string_pretty(&record)?;
+      fs::write(archived_record_path(extensions_root, &version), payload)?;
+    }
+  }
+  info!("[versions] archived v{version}");
+
+  prune_line_versions(extensions_root);
+  Ok(())
+}
+
+fn archived_versions(extensions_root: &Path) -> Vec<String> {
+  let Ok(entries) = fs::read_dir(versions_dir(extensions_root)) else {
+    return Vec::new();
+  };
+  let mut versions: Vec<String> = entries
+    .filter_map(|entry| entry.ok())
+    .filter(|entry| is_extension_dir(&entry.path()))
+    .filter_map(|entry| entry.file_name().to_str().map(|name| name.to_string()))
+    .filter(|name| is_valid_version(name))
+    .collect();
+  versions.sort_by(|a, b| compare_versions(b, a));
+  versions
+}
+
+fn prune_line_versions(extensions_root: &Path) {
+  for version in archived_versions(extensions_root)
+    .into_iter()
+    .skip(KEEP_LINE_VERSIONS)
+  {
+    let dir = versions_dir(extensions_root).join(&version);
+    if let Err(error) = fs::remove_dir_all(&dir) {
+      warn!("[versions] prune v{version} failed: {error:#}");
+      continue;
+    }
+    let _ = fs::remove_file(archived_record_path(extensions_root, &version));
+    info!("[versions] pruned v{version}");
+  }
+}
+
+/// Lists the active LINE extension followed by archived versions, newest first.
+pub(crate) fn list_line_versions(extensions_root: &Path, line_dir: &Path) -> Vec<LineVersion> {
+  let mut versions = Vec::new();
+  if let Some(version) = read_manifest_version(line_dir) {
+    let record = read_install_record(extensions_root);
+    versions.push(LineVersion {
+      version,
+      crx_sha256: record.as_ref().and_then(|record| record.crx_sha256.clone()),
+      installed_at: record.map(|record| record.installed_at).unwrap_or_default(),
+      active: true,
+    });
+  }
+  for version in archived_versions(extensions_root) {
+    let record = fs::read_to_string(archived_record_path(extensions_root, &version))
+      .ok()
+      .and_then(|raw| serde_json::from_str::<InstallRecord>(&raw).ok());
+    versions.push(LineVersion {
+      version,
+      crx_sha256: record.as_ref().and_then(|record| record.crx_sha256.clone()),
+      installed_at: record.map(|record| record.installed_at).unwrap_or_default(),
+      active: false,
+    });
+  }
+  versions
+}
+
+/// Swaps an archived version back into `line_dir`; the current one is archived.
+pub(crate) fn activate_line_version(
+  extensions_root: &Path,
+  line_dir: &Path,
+  version: &str,
+) -> Result<()> {
+  if !is_valid_version(version) {
+    return Err(anyhow!("invalid extension version: {version}"));
+  }
+  let source = versions_dir(extensions_root).join(version);
+  if !is_extension_dir(&source) {
+    return Err(anyhow!("extension v{version} is not archived"));
+  }
+
+  let record_path = archived_record_path(extensions_root, version);
+  let record = fs::read_to_string(&record_path)
+    .ok()
+    .and_then(|raw| serde_json::from_str::<InstallRecord>(&raw).ok());
+
+  let staging_dir = extensions_root.join(LINE_STAGING_DIR);
+  if staging_dir.exists() {
+    fs::remove_dir_all(&staging_dir)?;
+  }
+  fs::rename(&source, &staging_dir)?;
+  if let Err(error) = swap_in_staged(extensions_root, &staging_dir, line_dir, Replaced::Archive) {
+    let _ = fs::rename(&staging_dir, &source);
+    return Err(error);
+  }
+
+  let _ = fs::remove_file(&record_path);
+  let record = record.unwrap_or_else(|| InstallRecord {
+    version: Some(version.to_string()),
+    ..InstallRecord::default()
+  });
+  if let Err(error) = write_install_record(extensions_root, &record) {
+    warn!("[versions] install record write failed: {error:#}");
+  }
+  info!("[versions] activated v{version}");
+  Ok(())
+}
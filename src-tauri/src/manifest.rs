@@ -0,0 +1,379 @@
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const LOCALES_DIR: &str = "_locales";
+
+/// Permissions a new version requests that the installed one did not.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct PermissionDiff {
+  pub(crate) permissions: Vec<String>,
+  pub(crate) host_permissions: Vec<String>,
+}
+
+impl PermissionDiff {
+  pub(crate) fn is_empty(&self) -> bool {
+    self.permissions.is_empty() && self.host_permissions.is_empty()
+  }
+}
+
+/// `manifest.json` of an unpacked extension. Keys this struct doesn't model
+/// are kept in `extra` so `save` writes them back unchanged.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub(crate) struct ExtensionManifest {
+  pub(crate) name: String,
+  pub(crate) version: String,
+  pub(crate) manifest_version: u32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub(crate) default_locale: Option<String>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub(crate) permissions: Vec<Permission>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub(crate) host_permissions: Vec<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub(crate) background: Option<Background>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub(crate) content_scripts: Vec<ContentScript>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub(crate) web_accessible_resources: Vec<WebAccessibleResource>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub(crate) key: Option<String>,
+  #[serde(flatten)]
+  pub(crate) extra: Map<String, Value>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub(crate) struct Background {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub(crate) service_worker: Option<String>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub(crate) scripts: Vec<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub(crate) page: Option<String>,
+  #[serde(flatten)]
+  pub(crate) extra: Map<String, Value>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub(crate) struct ContentScript {
+  pub(crate) matches: Vec<String>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub(crate) js: Vec<String>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub(crate) css: Vec<String>,
+  #[serde(flatten)]
+  pub(crate) extra: Map<String, Value>,
+}
+
+/// A `permissions` entry: usually a name, but MV2 also allows objects such
+/// as `{ "fileSystem": ["write"] }`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub(crate) enum Permission {
+  Name(String),
+  Other(Value),
+}
+
+impl Permission {
+  /// The name, or an object's keys, as compared between versions and shown.
+  pub(crate) fn name(&self) -> String {
+    match self {
+      Permission::Name(name) => name.clone(),
+      Permission::Other(Value::Object(map)) => map.keys().cloned().collect::<Vec<_>>().join(","),
+      Permission::Other(other) => other.to_string(),
+    }
+  }
+}
+
+/// MV2 lists bare paths; MV3 lists `{ resources, matches }` objects.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub(crate) enum WebAccessibleResource {
+  Path(String),
+  Entry {
+    resources: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    matches: Vec<String>,
+    #[serde(flatten)]
+    extra: Map<String, Value>,
+  },
+}
+
+impl ExtensionManifest {
+  pub(crate) fn load(extension_dir: &Path) -> Result<Self> {
+    let path = extension_dir.join(MANIFEST_FILE);
+    let raw = fs::read_to_string(&path)
+      .map_err(|error| anyhow!("read {} failed: {error}", path.display()))?;
+    // Chrome tolerates a UTF-8 BOM in manifest.json.
+    let raw = raw.strip_prefix('\u{feff}').unwrap_or(&raw);
+    serde_json::from_str(raw).map_err(|error| anyhow!("parse {} failed: {error}", path.display()))
+  }
+
+  /// Just the `version` string, read without the typed model so a manifest
+  /// with fields it doesn't expect still counts as installed.
+  pub(crate) fn read_version(extension_dir: &Path) -> Option<String> {
+    let raw = fs::read_to_string(extension_dir.join(MANIFEST_FILE)).ok()?;
+    let value: Value = serde_json::from_str(raw.trim_start_matches('\u{feff}')).ok()?;
+    value
+      .get("version")
+      .and_then(|version| version.as_str())
+      .map(|version| version.to_string())
+  }
+
+  pub(crate) fn save(&self, extension_dir: &Path) -> Result<()> {
+    let pretty = serde_json::to_string_pretty(self)?;
+    fs::write(extension_dir.join(MANIFEST_FILE), pretty)?;
+    Ok(())
+  }
+
+  /// Checks the fields Chrome refuses to load an extension without. What
+  /// Chrome only warns about is logged.
+  pub(crate) fn validate(&self) -> Result<()> {
+    if self.name.trim().is_empty() {
+      return Err(anyhow!("manifest has no name"));
+    }
+    if !is_valid_version(&self.version) {
+      return Err(anyhow!("manifest version is invalid: {:?}", self.version));
+    }
+    match self.manifest_version {
+      2 => {}
+      3 => {
+        let uses_scripts = self
+          .background
+          .as_ref()
+          .is_some_and(|background| !background.scripts.is_empty() || background.page.is_some());
+        if uses_scripts {
+          warn!("[manifest] manifest_version 3 background should use service_worker");
+        }
+      }
+      other => return Err(anyhow!("unsupported manifest_version {other}")),
+    }
+    if let Some(script) = self
+      .content_scripts
+      .iter()
+      .find(|script| script.matches.is_empty())
+    {
+      return Err(anyhow!(
+        "content script {:?} has no matches",
+        script.js.first().or(script.css.first())
+      ));
+    }
+    Ok(())
+  }
+
+  /// `permissions` and `host_permissions` together; MV2 lists hosts in `permissions`.
+  pub(crate) fn all_permissions(&self) -> BTreeSet<String> {
+    self
+      .permissions
+      .iter()
+      .map(Permission::name)
+      .chain(self.host_permissions.iter().cloned())
+      .collect()
+  }
+
+  /// Compares against the currently installed manifest. A host moving from
+  /// `permissions` to `host_permissions` in an MV2 to MV3 update is not new.
+  pub(crate) fn added_permissions(&self, installed: &ExtensionManifest) -> PermissionDiff {
+    let existing = installed.all_permissions();
+    let added = |list: Vec<String>| {
+      list
+        .into_iter()
+        .filter(|permission| !existing.contains(permission))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+    };
+    PermissionDiff {
+      permissions: added(self.permissions.iter().map(Permission::name).collect()),
+      host_permissions: added(self.host_permissions.clone()),
+    }
+  }
+
+  /// The name with `__MSG_*__` placeholders resolved from `_locales`.
+  pub(crate) fn display_name(&self, extension_dir: &Path) -> String {
+    self.localize(extension_dir, &self.name)
+  }
+
+  /// Resolves a `__MSG_key__` string against the default locale. Anything
+  /// that isn't a placeholder, or has no message, is returned as is.
+  fn localize(&self, extension_dir: &Path, text: &str) -> String {
+    let Some(key) = text
+      .strip_prefix("__MSG_")
+      .and_then(|rest| rest.strip_suffix("__"))
+    else {
+      return text.to_string();
+    };
+    let Some(locale) = self.default_locale.as_deref() else {
+      return text.to_string();
+    };
+    let path = extension_dir
+      .join(LOCALES_DIR)
+      .join(locale)
+      .join("messages.json");
+    let messages: Map<String, Value> = match fs::read_to_string(&path)
+      .ok()
+      .and_then(|raw| serde_json::from_str(raw.trim_start_matches('\u{feff}')).ok())
+    {
+      Some(messages) => messages,
+      None => return text.to_string(),
+    };
+    // Message keys are case-insensitive.
+    messages
+      .iter()
+      .find(|(name, _)| name.eq_ignore_ascii_case(key))
+      .and_then(|(_, entry)| entry.get("message"))
+      .and_then(|message| message.as_str())
+      .map(|message| message.to_string())
+      .unwrap_or_else(|| text.to_string())
+  }
+}
+
+/// One to four dot-separated integers, each at most 65535.
+fn is_valid_version(version: &str) -> bool {
+  let parts: Vec<&str> = version.split('.').collect();
+  (1..=4).contains(&parts.len())
+    && parts.iter().all(|part| {
+      !part.is_empty()
+        && part.chars().all(|c| c.is_ascii_digit())
+        && (part.len() == 1 || !part.starts_with('0'))
+        && part.parse::<u32>().is_ok_and(|value| value <= 65535)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn write_manifest(dir: &Path, raw: &str) {
+    fs::write(dir.join(MANIFEST_FILE), raw).unwrap();
+  }
+
+  #[test]
+  fn loads_mv2_object_permissions() {
+    let old = tempfile::tempdir().unwrap();
+    write_manifest(
+      old.path(),
+      r#"{"name":"LINE","version":"2.0","manifest_version":2,
+          "permissions":["storage",{"fileSystem":["write"]},"https://*.line.me/*"]}"#,
+    );
+    let new = tempfile::tempdir().unwrap();
+    write_manifest(
+      new.path(),
+      r#"{"name":"LINE","version":"3.0","manifest_version":3,
+          "permissions":["storage","fileSystem","alarms"],
+          "host_permissions":["https://*.line.me/*"]}"#,
+    );
+
+    let installed = ExtensionManifest::load(old.path()).unwrap();
+    let staged = ExtensionManifest::load(new.path()).unwrap();
+    assert_eq!(
+      staged.added_permissions(&installed),
+      PermissionDiff {
+        permissions: vec!["alarms".to_string()],
+        host_permissions: Vec::new(),
+      }
+    );
+
+    installed.save(old.path()).unwrap();
+    let saved = fs::read_to_string(old.path().join(MANIFEST_FILE)).unwrap();
+    assert!(saved.contains(r#""fileSystem": ["#));
+  }
+
+  #[test]
+  fn reads_version_of_manifest_the_model_rejects() {
+    let dir = tempfile::tempdir().unwrap();
+    write_manifest(
+      dir.path(),
+      "\u{feff}{\"name\":\"LINE\",\"version\":\"3.7.1\",\"manifest_version\":\"3\"}",
+    );
+    assert!(ExtensionManifest::load(dir.path()).is_err());
+    assert_eq!(
+      ExtensionManifest::read_version(dir.path()).as_deref(),
+      Some("3.7.1")
+    );
+  }
+
+  fn manifest(raw: &str) -> ExtensionManifest {
+    serde_json::from_str(raw).unwrap()
+  }
+
+  #[test]
+  fn validates_what_chrome_requires() {
+    let valid = r#"{"name":"LINE","version":"3.7.1","manifest_version":3,
+      "background":{"service_worker":"background.js"},
+      "content_scripts":[{"matches":["https://*.line.me/*"],"js":["content.js"]}]}"#;
+    assert!(manifest(valid).validate().is_ok());
+
+    for invalid in [
+      r#"{"name":" ","version":"1.0","manifest_version":3}"#,
+      r#"{"name":"LINE","version":"1.0.0.0.0","manifest_version":3}"#,
+      r#"{"name":"LINE","version":"1.0","manifest_version":4}"#,
+      r#"{"name":"LINE","version":"1.0","manifest_version":3,
+        "content_scripts":[{"matches":[],"js":["content.js"]}]}"#,
+    ] {
+      assert!(manifest(invalid).validate().is_err(), "{invalid}");
+    }
+  }
+
+  #[test]
+  fn accepts_mv3_background_scripts_as_chrome_does() {
+    let raw = r#"{"name":"LINE","version":"3.7.1","manifest_version":3,
+      "background":{"scripts":["background.js"]}}"#;
+    assert!(manifest(raw).validate().is_ok());
+  }
+
+  #[test]
+  fn checks_version_format() {
+    for valid in ["1", "3.7.1", "0.0.0.1", "65535.65535.65535.65535"] {
+      assert!(is_valid_version(valid), "{valid}");
+    }
+    for invalid in [
+      "",
+      "1.",
+      ".1",
+      "1..2",
+      "01.2",
+      "1.65536",
+      "1.2.3.4.5",
+      "1.2b",
+      "-1",
+    ] {
+      assert!(!is_valid_version(invalid), "{invalid}");
+    }
+  }
+
+  #[test]
+  fn resolves_localized_names() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("_locales/ja")).unwrap();
+    fs::write(
+      dir.path().join("_locales/ja/messages.json"),
+      "\u{feff}{\"AppName\":{\"message\":\"LINE\"}}",
+    )
+    .unwrap();
+
+    let localized = manifest(
+      r#"{"name":"__MSG_appName__","version":"1.0","manifest_version":3,"default_locale":"ja"}"#,
+    );
+    assert_eq!(localized.display_name(dir.path()), "LINE");
+
+    let missing_key = manifest(
+      r#"{"name":"__MSG_other__","version":"1.0","manifest_version":3,"default_locale":"ja"}"#,
+    );
+    assert_eq!(missing_key.display_name(dir.path()), "__MSG_other__");
+
+    let no_locale = manifest(r#"{"name":"__MSG_appName__","version":"1.0","manifest_version":3}"#);
+    assert_eq!(no_locale.display_name(dir.path()), "__MSG_appName__");
+
+    let plain = manifest(r#"{"name":"Helper","version":"1.0","manifest_version":3}"#);
+    assert_eq!(plain.display_name(dir.path()), "Helper");
+  }
+}
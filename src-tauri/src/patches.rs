@@ -0,0 +1,477 @@
+use crate::update2::compare_versions;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+const BUILTIN_PATCHES: &str = include_str!("../patches/builtin.json");
+const USER_PATCHES_FILE: &str = "patches.json";
+/// Written into the patched extension so re-runs skip rules that were already
+/// applied to this version instead of applying them again.
+const APPLIED_PATCHES_FILE: &str = "refined-line.patches.json";
+
+/// One source modification of the LINE extension.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PatchRule {
+  pub(crate) id: String,
+  /// Path relative to the extension root.
+  pub(crate) file: String,
+  pub(crate) find: String,
+  /// Treat `find` as a regex; `replacement` may then use `$1`-style groups.
+  #[serde(default)]
+  pub(crate) regex: bool,
+  pub(crate) replacement: String,
+  /// Exact number of matches required; any non-zero count when unset.
+  #[serde(default)]
+  pub(crate) expected_count: Option<usize>,
+  /// Inclusive extension version range the rule applies to.
+  #[serde(default)]
+  pub(crate) min_version: Option<String>,
+  #[serde(default)]
+  pub(crate) max_version: Option<String>,
+}
+
+impl PatchRule {
+  fn applies_to(&self, version: Option<&str>) -> bool {
+    let Some(version) = version else {
+      return true;
+    };
+    let above_min = self
+      .min_version
+      .as_deref()
+      .map(|min| compare_versions(version, min) != Ordering::Less)
+      .unwrap_or(true);
+    let below_max = self
+      .max_version
+      .as_deref()
+      .map(|max| compare_versions(version, max) != Ordering::Greater)
+      .unwrap_or(true);
+    above_min && below_max
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub(crate) enum PatchStatus {
+  Applied { count: usize },
+  AlreadyApplied,
+  Skipped,
+  Failed { reason: String },
+}
+
+impl fmt::Display for PatchStatus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      PatchStatus::Applied { count } => write!(f, "applied ({count})"),
+      PatchStatus::AlreadyApplied => f.write_str("already applied"),
+      PatchStatus::Skipped => f.write_str("skipped"),
+      PatchStatus::Failed { reason } => write!(f, "failed: {reason}"),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PatchResult {
+  pub(crate) id: String,
+  pub(crate) file: String,
+  #[serde(flatten)]
+  pub(crate) status: PatchStatus,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct PatchReport {
+  pub(crate) results: Vec<PatchResult>,
+  /// Rules found already applied by a run that kept no marker were recorded.
+  #[serde(skip)]
+  marker_seeded: bool,
+}
+
+impl PatchReport {
+  /// Whether any file was rewritten, including the applied-patches marker.
+  pub(crate) fn changed_files(&self) -> bool {
+    self.marker_seeded
+      || self
+        .results
+        .iter()
+        .any(|result| matches!(result.status, PatchStatus::Applied { .. }))
+  }
+
+  fn push(&mut self, rule: &PatchRule, status: PatchStatus) {
+    self.results.push(PatchResult {
+      id: rule.id.clone(),
+      file: rule.file.clone(),
+      status,
+    });
+  }
+
+  pub(crate) fn log(&self, dir: &Path) {
+    for result in &self.results {
+      match &result.status {
+        PatchStatus::Failed { .. } => warn!(
+          "[patch] {} {}: {} ({})",
+          result.id,
+          result.file,
+          result.status,
+          dir.display()
+        ),
+        PatchStatus::Applied { .. } => {
+          info!("[patch] {} {}: {}", result.id, result.file, result.status)
+        }
+        _ => {}
+      }
+    }
+  }
+}
+
+/// Built-in rules followed by `extensions/patches.json`, if present.
+/// A user rule with the same id replaces the built-in one.
+pub(crate) fn load_patch_rules(extensions_root: &Path) -> Vec<PatchRule> {
+  let mut rules: Vec<PatchRule> = match serde_json::from_str(BUILTIN_PATCHES) {
+    Ok(rules) => rules,
+    Err(error) => {
+      warn!("[patch] builtin rules invalid: {error:#}");
+      Vec::new()
+    }
+  };
+
+  let user_path = extensions_root.join(USER_PATCHES_FILE);
+  let user_rules = match fs::read_to_string(&user_path) {
+    Ok(raw) => match serde_json::from_str::<Vec<PatchRule>>(&raw) {
+      Ok(user_rules) => user_rules,
+      Err(error) => {
+        warn!("[patch] {} invalid: {error:#}", user_path.display());
+        Vec::new()
+      }
+    },
+    Err(_) => Vec::new(),
+  };
+  for rule in user_rules {
+    match rules.iter_mut().find(|existing| existing.id == rule.id) {
+      Some(existing) => *existing = rule,
+      None => rules.push(rule),
+    }
+  }
+  rules
+}
+
+/// Rule ids applied to the extension folder, for the version they were applied to.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct AppliedPatches {
+  version: Option<String>,
+  ids: BTreeSet<String>,
+}
+
+/// Applies `rules` to the extension in `dir`, reading and writing each target
+/// file once. A rule that fails leaves its file as the other rules made it.
+/// Rules already applied to this version are not run again, so a replacement
+/// that still contains its `find` text cannot compound across launches.
+pub(crate) fn apply_patches(dir: &Path, rules: &[PatchRule]) -> Result<PatchReport> {
+  let version = crate::extensions::read_manifest_version(dir);
+  let mut applied = read_applied(dir);
+  if applied.version != version {
+    applied = AppliedPatches {
+      version: version.clone(),
+      ids: BTreeSet::new(),
+    };
+  }
+  let mut report = PatchReport::default();
+
+  let mut files: Vec<&str> = rules.iter().map(|rule| rule.file.as_str()).collect();
+  files.sort();
+  files.dedup();
+
+  for file in files {
+    let file_rules: Vec<&PatchRule> = rules.iter().filter(|rule| rule.file == file).collect();
+    let path = match relative_file(dir, file) {
+      Ok(path) => path,
+      Err(error) => {
+        for rule in file_rules {
+          report.push(rule, failed(format!("{error:#}")));
+        }
+        continue;
+      }
+    };
+
+    let mut content = match fs::read_to_string(&path) {
+      Ok(content) => content,
+      Err(error) => {
+        for rule in &file_rules {
+          let status = if rule.applies_to(version.as_deref()) {
+            failed(format!("read failed: {error}"))
+          } else {
+            PatchStatus::Skipped
+          };
+          report.push(rule, status);
+        }
+        continue;
+      }
+    };
+
+    let mut changed = false;
+    for rule in file_rules {
+      if !rule.applies_to(version.as_deref()) {
+        report.push(rule, PatchStatus::Skipped);
+        continue;
+      }
+      if applied.ids.contains(&rule.id) {
+        report.push(rule, PatchStatus::AlreadyApplied);
+        continue;
+      }
+      let status = match apply_rule(rule, &content) {
+        Ok(Some((updated, count))) => {
+          content = updated;
+          changed = true;
+          applied.ids.insert(rule.id.clone());
+          PatchStatus::Applied { count }
+        }
+        // Installs patched before the marker existed have the replacement
+        // in place of `find`.
+        Ok(None) if is_replacement_present(rule, &content) => {
+          applied.ids.insert(rule.id.clone());
+          report.marker_seeded = true;
+          PatchStatus::AlreadyApplied
+        }
+        Ok(None) => failed("no match".to_string()),
+        Err(error) => failed(format!("{error:#}")),
+      };
+      report.push(rule, status);
+    }
+
+    if changed {
+      fs::write(&path, content)?;
+    }
+  }
+
+  if report.changed_files() {
+    write_applied(dir, &applied)?;
+  }
+  Ok(report)
+}
+
+fn failed(reason: String) -> PatchStatus {
+  PatchStatus::Failed { reason }
+}
+
+/// Returns the patched text and match count, or `None` when nothing matched.
+fn apply_rule(rule: &PatchRule, content: &str) -> Result<Option<(String, usize)>> {
+  let (count, updated) = if rule.regex {
+    let pattern = Regex::new(&rule.find)?;
+    let count = pattern.find_iter(content).count();
+    (
+      count,
+      pattern
+        .replace_all(content, rule.replacement.as_str())
+        .into_owned(),
+    )
+  } else {
+    let count = content.matches(rule.find.as_str()).count();
+    (count, content.replace(&rule.find, &rule.replacement))
+  };
+  if count == 0 {
+    return Ok(None);
+  }
+  if let Some(expected) = rule.expected_count {
+    if count != expected {
+      return Err(anyhow!("expected {expected} matches, found {count}"));
+    }
+  }
+  Ok(Some((updated, count)))
+}
+
+/// Only literal replacements can be looked for; a regex replacement may
+/// depend on what its groups captured.
+fn is_replacement_present(rule: &PatchRule, content: &str) -> bool {
+  let literal = !rule.regex || !rule.replacement.contains('$');
+  literal && !rule.replacement.is_empty() && content.contains(&rule.replacement)
+}
+
+fn relative_file(dir: &Path, file: &str) -> Result<PathBuf> {
+  let relative = Path::new(file);
+  if !relative
+    .components()
+    .all(|component| matches!(component, Component::Normal(_)))
+  {
+    return Err(anyhow!("invalid patch target {file}"));
+  }
+  Ok(dir.join(relative))
+}
+
+fn read_applied(dir: &Path) -> AppliedPatches {
+  fs::read_to_string(dir.join(APPLIED_PATCHES_FILE))
+    .ok()
+    .and_then(|raw| serde_json::from_str(&raw).ok())
+    .unwrap_or_default()
+}
+
+fn write_applied(dir: &Path, applied: &AppliedPatches) -> Result<()> {
+  fs::write(
+    dir.join(APPLIED_PATCHES_FILE),
+    serde_json::to_string_pretty(applied)?,
+  )?;
+  Ok(())
+}
+
+/// Calls that wipe the LINE login when left in place; the built-in rules
+/// neutralise them. Each matches the same call as its rule, so other uses of
+/// the same API are not reported.
+const SESSION_CLEAR_CALLS: [&str; 3] = [
+  r"chrome\.storage\.local\.clear\s*\(",
+  r"indexedDB\.databases\s*\(",
+  r"caches\.delete\s*\(\s*CACHE_NAME\s*\)",
+];
+
+#[derive(Debug, thiserror::Error)]
+#[error("session clearing calls left unpatched: {}", .0.join(", "))]
+pub(crate) struct UnpatchedSessionClear(pub(crate) Vec<String>);
+
+/// Lists `file:line call` for every session-clearing call still present in
+/// the extension's JS after patching.
+pub(crate) fn find_session_clear_calls(dir: &Path) -> Result<Vec<String>> {
+  let patterns = SESSION_CLEAR_CALLS
+    .iter()
+    .map(|pattern| Regex::new(pattern))
+    .collect::<Result<Vec<_>, _>>()?;
+  let mut files = Vec::new();
+  collect_js_files(dir, &mut files)?;
+  files.sort();
+
+  let mut found = Vec::new();
+  for path in files {
+    let Ok(content) = fs::read_to_string(&path) else {
+      continue;
+    };
+    let relative = path
+      .strip_prefix(dir)
+      .unwrap_or(&path)
+      .display()
+      .to_string();
+    for (index, line) in content.lines().enumerate() {
+      for pattern in &patterns {
+        for hit in pattern.find_iter(line) {
+          found.push(format!("{relative}:{} {}", index + 1, hit.as_str()));
+        }
+      }
+    }
+  }
+  Ok(found)
+}
+
+fn collect_js_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    let path = entry.path();
+    let file_type = entry.file_type()?;
+    if file_type.is_dir() {
+      collect_js_files(&path, files)?;
+    } else if file_type.is_file() && path.extension().and_then(|ext| ext.to_str()) == Some("js") {
+      files.push(path);
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn write_extension(dir: &Path, version: &str, background: &str) {
+    fs::write(
+      dir.join("manifest.json"),
+      format!(r#"{{"name":"LINE","version":"{version}","manifest_version":3}}"#),
+    )
+    .unwrap();
+    fs::write(dir.join("background.js"), background).unwrap();
+  }
+
+  fn rule(find: &str, replacement: &str) -> PatchRule {
+    PatchRule {
+      id: "wrap-init".to_string(),
+      file: "background.js".to_string(),
+      find: find.to_string(),
+      regex: false,
+      replacement: replacement.to_string(),
+      expected_count: None,
+      min_version: None,
+      max_version: None,
+    }
+  }
+
+  #[test]
+  fn applied_rules_do_not_compound() {
+    let dir = tempfile::tempdir().unwrap();
+    write_extension(dir.path(), "3.7.1", "init();");
+    let rules = [rule("init();", "guard();init();")];
+
+    let report = apply_patches(dir.path(), &rules).unwrap();
+    assert_eq!(report.results[0].status, PatchStatus::Applied { count: 1 });
+    let report = apply_patches(dir.path(), &rules).unwrap();
+    assert_eq!(report.results[0].status, PatchStatus::AlreadyApplied);
+    assert!(!report.changed_files());
+    assert_eq!(
+      fs::read_to_string(dir.path().join("background.js")).unwrap(),
+      "guard();init();"
+    );
+  }
+
+  #[test]
+  fn finds_only_the_session_clearing_cache_delete() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+      dir.path().join("cache.js"),
+      "caches.delete('thumbnails');\ncaches.delete( CACHE_NAME );\n",
+    )
+    .unwrap();
+    assert_eq!(
+      find_session_clear_calls(dir.path()).unwrap(),
+      ["cache.js:2 caches.delete( CACHE_NAME )"]
+    );
+  }
+
+  #[test]
+  fn new_version_is_patched_again() {
+    let dir = tempfile::tempdir().unwrap();
+    write_extension(dir.path(), "3.7.1", "init();");
+    let rules = [rule("init();", "guard();init();")];
+    apply_patches(dir.path(), &rules).unwrap();
+
+    write_extension(dir.path(), "3.8.0", "init();");
+    let report = apply_patches(dir.path(), &rules).unwrap();
+    assert_eq!(report.results[0].status, PatchStatus::Applied { count: 1 });
+  }
+
+  #[test]
+  fn recognises_installs_patched_without_a_marker() {
+    let dir = tempfile::tempdir().unwrap();
+    write_extension(dir.path(), "3.7.1", "Promise.resolve().then(start);");
+    let rules = [rule("chrome.storage.local.clear()", "Promise.resolve()")];
+
+    let report = apply_patches(dir.path(), &rules).unwrap();
+    assert_eq!(report.results[0].status, PatchStatus::AlreadyApplied);
+    assert!(report.changed_files());
+    assert!(read_applied(dir.path()).ids.contains("wrap-init"));
+
+    let report = apply_patches(dir.path(), &rules).unwrap();
+    assert_eq!(report.results[0].status, PatchStatus::AlreadyApplied);
+    assert!(!report.changed_files());
+  }
+
+  #[test]
+  fn missing_find_without_its_replacement_still_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    write_extension(dir.path(), "3.7.1", "start();");
+    let report = apply_patches(dir.path(), &[rule("init();", "guard();init();")]).unwrap();
+    assert!(matches!(
+      report.results[0].status,
+      PatchStatus::Failed { .. }
+    ));
+    assert!(!report.changed_files());
+  }
+}
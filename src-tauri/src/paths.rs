@@ -6,6 +6,10 @@ pub(crate) fn app_data_root(app: &tauri::AppHandle) -> Result<PathBuf> {
   Ok(base.join(app_name))
 }
 
+pub(crate) fn extensions_root(app: &tauri::AppHandle) -> Result<PathBuf> {
+  Ok(app_data_root(app)?.join("extensions"))
+}
+
 pub(crate) fn profile_dir(app: &tauri::AppHandle) -> Result<PathBuf> {
   Ok(app_data_root(app)?.join("webview2-profile"))
 }
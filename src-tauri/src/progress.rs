@@ -0,0 +1,135 @@
+use crate::retry::Clock;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tauri::{Emitter, Manager};
+
+pub(crate) const EXTENSION_PROGRESS_EVENT: &str = "extension-progress";
+
+/// Startup progress of the LINE extension, rendered by `src/index.html`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(
+  tag = "phase",
+  rename_all = "camelCase",
+  rename_all_fields = "camelCase"
+)]
+pub(crate) enum ExtensionProgress {
+  Checking {
+    source: String,
+  },
+  Downloading {
+    received: u64,
+    total: Option<u64>,
+  },
+  Verifying,
+  Installing {
+    version: Option<String>,
+  },
+  Retrying {
+    label: String,
+    attempt: usize,
+    max_attempts: usize,
+    /// Unix time in milliseconds.
+    next_retry_at: u64,
+    error: String,
+    can_use_cached: bool,
+  },
+  Opening,
+  Failed {
+    message: String,
+  },
+}
+
+/// What the loading screen asked for while a retry is pending.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum RetryAction {
+  RetryNow,
+  UseCached,
+  Cancel,
+}
+
+#[derive(Default)]
+pub(crate) struct ProgressState {
+  last: Mutex<Option<ExtensionProgress>>,
+  action: Mutex<Option<RetryAction>>,
+  action_changed: Condvar,
+}
+
+impl ProgressState {
+  pub(crate) fn last(&self) -> Option<ExtensionProgress> {
+    self.last.lock().ok().and_then(|last| last.clone())
+  }
+
+  pub(crate) fn request(&self, action: RetryAction) {
+    if let Ok(mut slot) = self.action.lock() {
+      *slot = Some(action);
+      self.action_changed.notify_all();
+    }
+  }
+}
+
+/// Emits progress to the UI and remembers the last event for late listeners.
+#[derive(Clone)]
+pub(crate) struct ProgressReporter {
+  app: tauri::AppHandle,
+  state: Arc<ProgressState>,
+}
+
+impl ProgressReporter {
+  pub(crate) fn new(app: &tauri::AppHandle) -> Self {
+    let state = match app.try_state::<Arc<ProgressState>>() {
+      Some(state) => state.inner().clone(),
+      None => {
+        let state = Arc::new(ProgressState::default());
+        app.manage(state.clone());
+        state
+      }
+    };
+    Self {
+      app: app.clone(),
+      state,
+    }
+  }
+
+  pub(crate) fn emit(&self, progress: ExtensionProgress) {
+    if let Ok(mut last) = self.state.last.lock() {
+      *last = Some(progress.clone());
+    }
+    let _ = self.app.emit(EXTENSION_PROGRESS_EVENT, progress);
+  }
+
+  /// Sleeps until `wait` elapses or the user picks an action on the loading screen.
+  pub(crate) fn wait_for_retry(&self, wait: Duration) -> Option<RetryAction> {
+    let Ok(mut slot) = self.state.action.lock() else {
+      std::thread::sleep(wait);
+      return None;
+    };
+    *slot = None;
+    let result = self
+      .state
+      .action_changed
+      .wait_timeout_while(slot, wait, |action| action.is_none());
+    match result {
+      Ok((mut slot, _)) => slot.take(),
+      Err(_) => None,
+    }
+  }
+}
+
+impl Clock for ProgressReporter {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+
+  fn wait(&self, duration: Duration) -> Option<RetryAction> {
+    self.wait_for_retry(duration)
+  }
+}
+
+pub(crate) fn unix_now_millis() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_millis() as u64)
+    .unwrap_or_default()
+}
@@ -0,0 +1,350 @@
+use crate::progress::RetryAction;
+use anyhow::Result;
+use log::info;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::{Duration, Instant};
+
+/// How often and how long a failing network step is retried.
+#[derive(Clone, Debug)]
+pub(crate) struct RetryPolicy {
+  /// Attempts including the first one.
+  pub(crate) max_attempts: usize,
+  pub(crate) initial_delay: Duration,
+  pub(crate) max_delay: Duration,
+  /// No retry is started that would end later than this after the first attempt.
+  pub(crate) max_elapsed: Duration,
+  /// Share of each delay that is randomised, from 0 to 1.
+  pub(crate) jitter: f64,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    Self {
+      max_attempts: 6,
+      initial_delay: Duration::from_secs(2),
+      max_delay: Duration::from_secs(60),
+      max_elapsed: Duration::from_secs(5 * 60),
+      jitter: 0.5,
+    }
+  }
+}
+
+impl RetryPolicy {
+  /// Wait after the `failures`-th failure: `initial_delay` doubled per failure
+  /// up to `max_delay`, with the `jitter` share scaled by `random` in `0..1`.
+  pub(crate) fn delay(&self, failures: usize, random: f64) -> Duration {
+    let doublings = failures.saturating_sub(1).min(31) as u32;
+    let base = self
+      .initial_delay
+      .saturating_mul(1 << doublings)
+      .min(self.max_delay);
+    let jitter = self.jitter.clamp(0.0, 1.0);
+    base.mul_f64(1.0 - jitter + jitter * random.clamp(0.0, 1.0))
+  }
+}
+
+/// Time source for `retry_with_backoff`. The app waits on the loading screen;
+/// tests substitute a clock that advances instantly.
+pub(crate) trait Clock {
+  fn now(&self) -> Instant;
+
+  /// Blocks for up to `duration`, returning early with an action picked on
+  /// the loading screen.
+  fn wait(&self, duration: Duration) -> Option<RetryAction>;
+
+  /// Uniform in `0..1`, used for jitter.
+  fn random(&self) -> f64 {
+    let bits = RandomState::new().hash_one(self.now());
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+  }
+}
+
+/// A failed attempt that is about to be retried.
+pub(crate) struct RetryWait<'a> {
+  pub(crate) attempt: usize,
+  pub(crate) max_attempts: usize,
+  pub(crate) delay: Duration,
+  pub(crate) error: &'a anyhow::Error,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("cancelled")]
+pub(crate) struct RetryCancelled;
+
+#[derive(Debug, thiserror::Error)]
+#[error("use cached version requested")]
+pub(crate) struct UseCachedRequested;
+
+/// Runs `action` until it succeeds, `policy` runs out, or the loading screen
+/// cancels. "Retry now" ends the current wait early; "use cached" ends with
+/// `UseCachedRequested` when `can_use_cached`. Errors for which `is_final`
+/// holds are returned without retrying. `on_retry` is called before each wait.
+pub(crate) fn retry_with_backoff<T>(
+  policy: &RetryPolicy,
+  clock: &dyn Clock,
+  can_use_cached: bool,
+  is_final: impl Fn(&anyhow::Error) -> bool,
+  mut on_retry: impl FnMut(&RetryWait),
+  mut action: impl FnMut() -> Result<T>,
+) -> Result<T> {
+  let started = clock.now();
+  let mut failures = 0;
+  loop {
+    let error = match action() {
+      Ok(value) => return Ok(value),
+      // A retry inside `action` that the user already stopped.
+      Err(error)
+        if error.is::<RetryCancelled>() || error.is::<UseCachedRequested>() || is_final(&error) =>
+      {
+        return Err(error)
+      }
+      Err(error) => error,
+    };
+    failures += 1;
+    if failures >= policy.max_attempts {
+      return Err(error.context(format!("gave up after {failures} attempts")));
+    }
+    let delay = policy.delay(failures, clock.random());
+    let elapsed = clock.now().saturating_duration_since(started);
+    if elapsed + delay > policy.max_elapsed {
+      return Err(error.context(format!(
+        "gave up after {failures} attempts in {}s",
+        elapsed.as_secs()
+      )));
+    }
+
+    on_retry(&RetryWait {
+      attempt: failures,
+      max_attempts: policy.max_attempts,
+      delay,
+      error: &error,
+    });
+    match clock.wait(delay) {
+      Some(RetryAction::Cancel) => return Err(RetryCancelled.into()),
+      Some(RetryAction::UseCached) if can_use_cached => return Err(UseCachedRequested.into()),
+      Some(action) => info!("[update] {action:?} requested"),
+      None => {}
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use anyhow::anyhow;
+  use std::cell::{Cell, RefCell};
+  use std::collections::VecDeque;
+
+  /// Advances only when waited on. A scripted action ends its wait at once.
+  struct FakeClock {
+    started: Instant,
+    elapsed: Cell<Duration>,
+    random: f64,
+    actions: RefCell<VecDeque<RetryAction>>,
+    waits: RefCell<Vec<Duration>>,
+  }
+
+  impl FakeClock {
+    fn new(random: f64, actions: &[RetryAction]) -> Self {
+      Self {
+        started: Instant::now(),
+        elapsed: Cell::new(Duration::ZERO),
+        random,
+        actions: RefCell::new(actions.iter().copied().collect()),
+        waits: RefCell::new(Vec::new()),
+      }
+    }
+  }
+
+  impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+      self.started + self.elapsed.get()
+    }
+
+    fn wait(&self, duration: Duration) -> Option<RetryAction> {
+      self.waits.borrow_mut().push(duration);
+      let action = self.actions.borrow_mut().pop_front();
+      if action.is_none() {
+        self.elapsed.set(self.elapsed.get() + duration);
+      }
+      action
+    }
+
+    fn random(&self) -> f64 {
+      self.random
+    }
+  }
+
+  fn policy() -> RetryPolicy {
+    RetryPolicy {
+      max_attempts: 10,
+      initial_delay: Duration::from_secs(1),
+      max_delay: Duration::from_secs(8),
+      max_elapsed: Duration::from_secs(3600),
+      jitter: 0.0,
+    }
+  }
+
+  /// Runs an action that fails `failures` times before succeeding.
+  fn run(
+    policy: &RetryPolicy,
+    clock: &FakeClock,
+    can_use_cached: bool,
+    failures: usize,
+  ) -> (Result<usize>, usize) {
+    let mut attempts = 0;
+    let result = retry_with_backoff(
+      policy,
+      clock,
+      can_use_cached,
+      |_| false,
+      |_| {},
+      || {
+        attempts += 1;
+        if attempts > failures {
+          Ok(attempts)
+        } else {
+          Err(anyhow!("attempt {attempts} failed"))
+        }
+      },
+    );
+    (result, attempts)
+  }
+
+  fn secs(values: &[u64]) -> Vec<Duration> {
+    values.iter().copied().map(Duration::from_secs).collect()
+  }
+
+  #[test]
+  fn backs_off_exponentially_up_to_max_delay() {
+    let clock = FakeClock::new(0.5, &[]);
+    let (result, attempts) = run(&policy(), &clock, false, 6);
+    assert_eq!(result.unwrap(), 7);
+    assert_eq!(attempts, 7);
+    assert_eq!(*clock.waits.borrow(), secs(&[1, 2, 4, 8, 8, 8]));
+  }
+
+  #[test]
+  fn jitter_stays_within_its_share_of_the_delay() {
+    let policy = RetryPolicy {
+      jitter: 0.5,
+      ..policy()
+    };
+    assert_eq!(policy.delay(3, 0.0), Duration::from_secs(2));
+    assert_eq!(policy.delay(3, 1.0), Duration::from_secs(4));
+    assert_eq!(policy.delay(3, 0.5), Duration::from_secs(3));
+    // Out-of-range input is clamped rather than overshooting.
+    assert_eq!(policy.delay(3, 7.0), Duration::from_secs(4));
+    assert_eq!(policy.delay(40, 1.0), Duration::from_secs(8));
+  }
+
+  #[test]
+  fn gives_up_after_max_attempts() {
+    let policy = RetryPolicy {
+      max_attempts: 3,
+      ..policy()
+    };
+    let clock = FakeClock::new(0.5, &[]);
+    let (result, attempts) = run(&policy, &clock, false, usize::MAX);
+    let error = format!("{:#}", result.unwrap_err());
+    assert!(error.starts_with("gave up after 3 attempts"), "{error}");
+    assert_eq!(attempts, 3);
+    assert_eq!(clock.waits.borrow().len(), 2);
+  }
+
+  #[test]
+  fn gives_up_before_a_wait_that_would_pass_max_elapsed() {
+    let policy = RetryPolicy {
+      max_elapsed: Duration::from_secs(10),
+      ..policy()
+    };
+    let clock = FakeClock::new(0.5, &[]);
+    let (result, attempts) = run(&policy, &clock, false, usize::MAX);
+    // 1 + 2 + 4 seconds fit; the next 8 would end at 15.
+    let error = format!("{:#}", result.unwrap_err());
+    assert!(
+      error.starts_with("gave up after 4 attempts in 7s"),
+      "{error}"
+    );
+    assert_eq!(attempts, 4);
+    assert_eq!(*clock.waits.borrow(), secs(&[1, 2, 4]));
+  }
+
+  #[test]
+  fn retry_now_ends_the_wait_and_keeps_going() {
+    let policy = RetryPolicy {
+      max_elapsed: Duration::from_secs(20),
+      ..policy()
+    };
+    let clock = FakeClock::new(0.5, &[RetryAction::RetryNow; 3]);
+    let (result, attempts) = run(&policy, &clock, false, 5);
+    assert_eq!(result.unwrap(), 6);
+    assert_eq!(attempts, 6);
+    // Waiting all of 1 + 2 + 4 + 8 + 8 seconds would have passed
+    // `max_elapsed`, but the skipped waits took no time.
+    assert_eq!(*clock.waits.borrow(), secs(&[1, 2, 4, 8, 8]));
+    assert_eq!(clock.elapsed.get(), Duration::from_secs(16));
+  }
+
+  #[test]
+  fn cancel_stops_with_retry_cancelled() {
+    let clock = FakeClock::new(0.5, &[RetryAction::Cancel]);
+    let (result, attempts) = run(&policy(), &clock, true, usize::MAX);
+    assert!(result.unwrap_err().is::<RetryCancelled>());
+    assert_eq!(attempts, 1);
+  }
+
+  #[test]
+  fn use_cached_stops_only_when_allowed() {
+    let clock = FakeClock::new(0.5, &[RetryAction::UseCached]);
+    let (result, attempts) = run(&policy(), &clock, true, usize::MAX);
+    assert!(result.unwrap_err().is::<UseCachedRequested>());
+    assert_eq!(attempts, 1);
+
+    let clock = FakeClock::new(0.5, &[RetryAction::UseCached]);
+    let (result, attempts) = run(&policy(), &clock, false, 2);
+    assert_eq!(result.unwrap(), 3);
+    assert_eq!(attempts, 3);
+  }
+
+  #[test]
+  fn stop_requests_from_a_nested_retry_pass_through() {
+    let clock = FakeClock::new(0.5, &[]);
+    let mut attempts = 0;
+    let result: Result<()> = retry_with_backoff(
+      &policy(),
+      &clock,
+      true,
+      |_| false,
+      |_| {},
+      || {
+        attempts += 1;
+        Err(RetryCancelled.into())
+      },
+    );
+    assert!(result.unwrap_err().is::<RetryCancelled>());
+    assert_eq!(attempts, 1);
+    assert!(clock.waits.borrow().is_empty());
+  }
+
+  #[test]
+  fn final_errors_are_not_retried() {
+    let clock = FakeClock::new(0.5, &[]);
+    let mut attempts = 0;
+    let result: Result<()> = retry_with_backoff(
+      &policy(),
+      &clock,
+      false,
+      |error| error.to_string() == "refused",
+      |_| {},
+      || {
+        attempts += 1;
+        Err(anyhow!("refused"))
+      },
+    );
+    assert_eq!(result.unwrap_err().to_string(), "refused");
+    assert_eq!(attempts, 1);
+    assert!(clock.waits.borrow().is_empty());
+  }
+}
@@ -12,6 +12,40 @@ pub(crate) struct AppSettings {
   pub(crate) start_minimized: bool,
   pub(crate) content_protection: bool,
   pub(crate) log_level: String,
+  pub(crate) pinned_line_version: Option<String>,
+  /// Keep the current LINE extension rather than install one whose
+  /// session-clearing calls could not be patched.
+  pub(crate) refuse_unpatched_session_clear: bool,
+  /// Apply LINE extension updates that request new permissions without asking.
+  pub(crate) always_allow_line_permissions: bool,
+  /// Folder names under `extensions/user` that are not loaded.
+  pub(crate) disabled_user_extensions: Vec<String>,
+  /// Hours between background extension update checks; 0 checks only once
+  /// per launch.
+  pub(crate) extension_update_interval_hours: u64,
+  pub(crate) network: NetworkSettings,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub(crate) struct NetworkSettings {
+  /// `http://` or `socks5://` proxy. Falls back to `HTTPS_PROXY` when unset.
+  pub(crate) proxy_url: Option<String>,
+  /// PEM file with extra root certificates to trust.
+  pub(crate) root_ca_file: Option<String>,
+  pub(crate) connect_timeout_secs: u64,
+  pub(crate) read_timeout_secs: u64,
+}
+
+impl Default for NetworkSettings {
+  fn default() -> Self {
+    Self {
+      proxy_url: None,
+      root_ca_file: None,
+      connect_timeout_secs: 10,
+      read_timeout_secs: 30,
+    }
+  }
 }
 
 impl Default for AppSettings {
@@ -21,6 +55,12 @@ impl Default for AppSettings {
       start_minimized: false,
       content_protection: true,
       log_level: crate::logger::DEFAULT_LOG_LEVEL.to_string(),
+      pinned_line_version: None,
+      refuse_unpatched_session_clear: false,
+      always_allow_line_permissions: false,
+      disabled_user_extensions: Vec::new(),
+      extension_update_interval_hours: 6,
+      network: NetworkSettings::default(),
     }
   }
 }
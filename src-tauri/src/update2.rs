@@ -0,0 +1,281 @@
+use crate::http_client::http_agent;
+use anyhow::{anyhow, Result};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::cmp::Ordering;
+use std::io::Read;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum UpdateStatus {
+  Ok,
+  NoUpdate,
+  Error(String),
+}
+
+/// The `<updatecheck>` element of an update2 response for a single app.
+#[derive(Clone, Debug)]
+pub(crate) struct UpdateManifest {
+  pub(crate) status: UpdateStatus,
+  pub(crate) version: Option<String>,
+  pub(crate) codebase: Option<String>,
+  pub(crate) hash_sha256: Option<String>,
+  pub(crate) size: Option<u64>,
+  /// `ETag` of the response, sent back as `If-None-Match` on the next check.
+  pub(crate) etag: Option<String>,
+}
+
+impl UpdateManifest {
+  /// What a `304 Not Modified` means: nothing changed since the check that
+  /// stored `etag`, which only ever reported no update.
+  pub(crate) fn not_modified(etag: &str) -> Self {
+    Self {
+      status: UpdateStatus::NoUpdate,
+      version: None,
+      codebase: None,
+      hash_sha256: None,
+      size: None,
+      etag: Some(etag.to_string()),
+    }
+  }
+}
+
+pub(crate) fn build_update_url(base: &str, extension_id: &str, version: Option<&str>) -> String {
+  let mut x = format!("id%3D{extension_id}%26installsource%3Dondemand");
+  if let Some(version) = version {
+    x.push_str(&format!("%26v%3D{version}"));
+  }
+  x.push_str("%26uc");
+  format!("{base}?os=win&arch=x64&os_arch=x86_64&nacl_arch=x86-64&prod=chromecrx&prodchannel=unknown&prodversion=120.0.0.0&acceptformat=crx3&x={x}")
+}
+
+pub(crate) fn check_update(
+  url: &str,
+  extension_id: &str,
+  etag: Option<&str>,
+) -> Result<UpdateManifest> {
+  let mut request = http_agent().get(url);
+  if let Some(etag) = etag {
+    request = request.set("If-None-Match", etag);
+  }
+  let response = request
+    .call()
+    .map_err(|error| anyhow!("update check failed: {error}"))?;
+  if let (304, Some(etag)) = (response.status(), etag) {
+    return Ok(UpdateManifest::not_modified(etag));
+  }
+
+  let response_etag = response.header("ETag").map(str::to_string);
+  let mut body = String::new();
+  response.into_reader().read_to_string(&mut body)?;
+  let mut manifest = parse_update_manifest(&body, extension_id)?;
+  manifest.etag = response_etag;
+  Ok(manifest)
+}
+
+pub(crate) fn parse_update_manifest(xml: &str, extension_id: &str) -> Result<UpdateManifest> {
+  let mut reader = Reader::from_str(xml);
+  let mut in_app = false;
+  loop {
+    match reader.read_event()? {
+      Event::Start(element) if element.local_name().as_ref() == b"app" => {
+        in_app = attribute(&element, b"appid")?.as_deref() == Some(extension_id);
+      }
+      Event::Empty(element)
+        if element.local_name().as_ref() == b"app"
+          && attribute(&element, b"appid")?.as_deref() == Some(extension_id) =>
+      {
+        let status = attribute(&element, b"status")?.unwrap_or_default();
+        return Err(anyhow!("update check failed: app status {status}"));
+      }
+      Event::End(element) if element.local_name().as_ref() == b"app" => {
+        in_app = false;
+      }
+      Event::Start(element) | Event::Empty(element)
+        if in_app && element.local_name().as_ref() == b"updatecheck" =>
+      {
+        return parse_updatecheck(&element);
+      }
+      Event::Eof => break,
+      _ => {}
+    }
+  }
+
+  Err(anyhow!(
+    "update check failed: no updatecheck for {extension_id}"
+  ))
+}
+
+fn parse_updatecheck(element: &BytesStart) -> Result<UpdateManifest> {
+  let status = match attribute(element, b"status")?.as_deref() {
+    Some("ok") => UpdateStatus::Ok,
+    Some("noupdate") => UpdateStatus::NoUpdate,
+    Some(other) => UpdateStatus::Error(other.to_string()),
+    None => UpdateStatus::Error("missing status".to_string()),
+  };
+  let size = match attribute(element, b"size")? {
+    Some(raw) => Some(
+      raw
+        .parse::<u64>()
+        .map_err(|error| anyhow!("invalid updatecheck size {raw}: {error}"))?,
+    ),
+    None => None,
+  };
+
+  Ok(UpdateManifest {
+    status,
+    version: attribute(element, b"version")?,
+    codebase: attribute(element, b"codebase")?,
+    hash_sha256: attribute(element, b"hash_sha256")?.map(|hash| hash.to_ascii_lowercase()),
+    size,
+    etag: None,
+  })
+}
+
+fn attribute(element: &BytesStart, name: &[u8]) -> Result<Option<String>> {
+  match element.try_get_attribute(name)? {
+    Some(attr) => Ok(Some(attr.unescape_value()?.into_owned())),
+    None => Ok(None),
+  }
+}
+
+/// Compares dotted extension versions numerically (`1.10.0` > `1.9.3`).
+pub(crate) fn compare_versions(left: &str, right: &str) -> Ordering {
+  let parse = |value: &str| -> Vec<u64> {
+    value
+      .split('.')
+      .map(|part| part.trim().parse::<u64>().unwrap_or(0))
+      .collect()
+  };
+  let left = parse(left);
+  let right = parse(right);
+  let len = left.len().max(right.len());
+  for index in 0..len {
+    let a = left.get(index).copied().unwrap_or(0);
+    let b = right.get(index).copied().unwrap_or(0);
+    match a.cmp(&b) {
+      Ordering::Equal => continue,
+      other => return other,
+    }
+  }
+  Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::TcpListener;
+  use std::sync::mpsc;
+  use std::thread;
+
+  const ID: &str = "ophjlpahpchlmihnnnihgmmeilfjmjjc";
+
+  fn response(app: &str) -> String {
+    format!(
+      r#"<?xml version="1.0" encoding="UTF-8"?>
+<gupdate xmlns="http://www.google.com/update2/response" protocol="2.0" server="prod">
+  <daystart elapsed_days="6868" elapsed_seconds="3600"/>
+  {app}
+</gupdate>"#
+    )
+  }
+
+  const OK_APP: &str = r#"<app appid="ophjlpahpchlmihnnnihgmmeilfjmjjc" cohort="1::" status="ok">
+    <updatecheck codebase="https://example.com/line-3.7.1.crx" fp="1.abc" hash_sha256="AB12CD" size="4096" status="ok" version="3.7.1"/>
+  </app>"#;
+
+  /// Answers one request on a local port with `reply`, and hands back the
+  /// request head it received.
+  fn serve_once(reply: String) -> (String, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!(
+      "http://{}/service/update2/crx",
+      listener.local_addr().unwrap()
+    );
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+      let (stream, _) = listener.accept().unwrap();
+      let mut reader = BufReader::new(stream);
+      let mut head = String::new();
+      loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line.trim().is_empty() {
+          break;
+        }
+        head.push_str(&line);
+      }
+      reader.get_mut().write_all(reply.as_bytes()).unwrap();
+      let _ = tx.send(head);
+    });
+    (url, rx)
+  }
+
+  #[test]
+  fn parses_ok_updatecheck() {
+    let manifest = parse_update_manifest(&response(OK_APP), ID).unwrap();
+    assert_eq!(manifest.status, UpdateStatus::Ok);
+    assert_eq!(manifest.version.as_deref(), Some("3.7.1"));
+    assert_eq!(
+      manifest.codebase.as_deref(),
+      Some("https://example.com/line-3.7.1.crx")
+    );
+    assert_eq!(manifest.hash_sha256.as_deref(), Some("ab12cd"));
+    assert_eq!(manifest.size, Some(4096));
+  }
+
+  #[test]
+  fn parses_noupdate() {
+    let app = format!(r#"<app appid="{ID}" status="ok"><updatecheck status="noupdate"/></app>"#);
+    let manifest = parse_update_manifest(&response(&app), ID).unwrap();
+    assert_eq!(manifest.status, UpdateStatus::NoUpdate);
+    assert_eq!(manifest.codebase, None);
+  }
+
+  #[test]
+  fn rejects_app_error_status() {
+    let app = format!(r#"<app appid="{ID}" status="error-unknownApplication"/>"#);
+    let error = parse_update_manifest(&response(&app), ID).unwrap_err();
+    assert!(error.to_string().contains("error-unknownApplication"));
+  }
+
+  #[test]
+  fn ignores_other_appid() {
+    let error =
+      parse_update_manifest(&response(OK_APP), "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa").unwrap_err();
+    assert!(error.to_string().contains("no updatecheck"));
+  }
+
+  #[test]
+  fn rejects_invalid_size() {
+    let xml = response(&OK_APP.replace(r#"size="4096""#, r#"size="-1""#));
+    let error = parse_update_manifest(&xml, ID).unwrap_err();
+    assert!(error.to_string().contains("invalid updatecheck size"));
+  }
+
+  #[test]
+  fn check_update_reads_response_and_etag() {
+    let body = response(OK_APP);
+    let (url, request) = serve_once(format!(
+      "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+      body.len()
+    ));
+    let manifest = check_update(&url, ID, None).unwrap();
+    assert_eq!(manifest.version.as_deref(), Some("3.7.1"));
+    assert_eq!(manifest.etag.as_deref(), Some("\"v1\""));
+    let head = request.recv().unwrap().to_ascii_lowercase();
+    assert!(!head.contains("if-none-match"));
+  }
+
+  #[test]
+  fn check_update_treats_not_modified_as_noupdate() {
+    let (url, request) = serve_once(
+      "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n".to_string(),
+    );
+    let manifest = check_update(&url, ID, Some("\"v1\"")).unwrap();
+    assert_eq!(manifest.status, UpdateStatus::NoUpdate);
+    assert_eq!(manifest.etag.as_deref(), Some("\"v1\""));
+    let head = request.recv().unwrap().to_ascii_lowercase();
+    assert!(head.contains("if-none-match: \"v1\""));
+  }
+}
@@ -0,0 +1,183 @@
+use crate::extensions::{check_line_update, unix_now};
+use crate::paths::extensions_root;
+use crate::settings::load_settings;
+use crate::update_sources::UpdateSource;
+use crate::user_extensions::check_store_updates;
+use anyhow::Result;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const UPDATE_CHECK_FILE: &str = "update-check.json";
+/// Longest single sleep, so a changed interval setting is picked up.
+const POLL_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// `extensions/update-check.json`: when the background check last ran, and
+/// the `ETag` of each update check that found nothing new.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+struct UpdateCheckState {
+  last_checked_at: u64,
+  /// Keyed by `etag_key`.
+  cached_etags: BTreeMap<String, CachedEtag>,
+}
+
+/// The check URL carries the installed version, so an `ETag` is only valid
+/// while that version is still installed.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct CachedEtag {
+  version: String,
+  etag: String,
+}
+
+fn state_path(extensions_root: &Path) -> PathBuf {
+  extensions_root.join(UPDATE_CHECK_FILE)
+}
+
+fn read_update_check_state(extensions_root: &Path) -> UpdateCheckState {
+  fs::read_to_string(state_path(extensions_root))
+    .ok()
+    .and_then(|raw| serde_json::from_str(&raw).ok())
+    .unwrap_or_default()
+}
+
+fn write_update_check_state(extensions_root: &Path, state: &UpdateCheckState) -> Result<()> {
+  fs::create_dir_all(extensions_root)?;
+  fs::write(
+    state_path(extensions_root),
+    serde_json::to_string_pretty(state)?,
+  )?;
+  Ok(())
+}
+
+pub(crate) fn etag_key(extension_id: &str, source: &UpdateSource) -> String {
+  format!("{extension_id} {source}")
+}
+
+/// The `ETag` stored for `key`, unless it was recorded for another version.
+pub(crate) fn cached_etag(
+  extensions_root: &Path,
+  key: &str,
+  current_version: Option<&str>,
+) -> Option<String> {
+  let cached = read_update_check_state(extensions_root)
+    .cached_etags
+    .remove(key)?;
+  (Some(cached.version.as_str()) == current_version).then_some(cached.etag)
+}
+
+/// Stores the `ETag` of a check that found nothing new for
+/// `current_version`, or forgets it when either is `None`.
+pub(crate) fn remember_etag(
+  extensions_root: &Path,
+  key: &str,
+  current_version: Option<&str>,
+  etag: Option<&str>,
+) {
+  let mut state = read_update_check_state(extensions_root);
+  let changed = match current_version.zip(etag) {
+    Some((version, etag)) => {
+      let cached = CachedEtag {
+        version: version.to_string(),
+        etag: etag.to_string(),
+      };
+      if state.cached_etags.get(key) == Some(&cached) {
+        false
+      } else {
+        state.cached_etags.insert(key.to_string(), cached);
+        true
+      }
+    }
+    None => state.cached_etags.remove(key).is_some(),
+  };
+  if changed {
+    if let Err(error) = write_update_check_state(extensions_root, &state) {
+      warn!("[update] save etag failed: {error:#}");
+    }
+  }
+}
+
+/// Re-runs the LINE and Web Store extension update checks every
+/// `extensionUpdateIntervalHours`. The last run is persisted, so a relaunch
+/// within the interval waits out the rest of it instead of checking again.
+/// With the interval at 0 the extensions are still checked once per launch,
+/// since an installed LINE extension is otherwise never checked at all.
+pub(crate) fn spawn_extension_update_scheduler(app: &tauri::AppHandle) {
+  let app = app.clone();
+  std::thread::spawn(move || {
+    let mut at_launch = true;
+    loop {
+      let launch = std::mem::take(&mut at_launch);
+      let settings = load_settings(&app).unwrap_or_default();
+      let root = match extensions_root(&app) {
+        Ok(root) => root,
+        Err(error) => {
+          warn!("[update] scheduler stopped: {error:#}");
+          return;
+        }
+      };
+      let wait = match next_check_delay(&root, settings.extension_update_interval_hours) {
+        Some(wait) => wait,
+        None if launch => Duration::ZERO,
+        None => {
+          std::thread::sleep(POLL_INTERVAL);
+          continue;
+        }
+      };
+      if !wait.is_zero() {
+        debug!("[update] next check in {}s", wait.as_secs());
+        std::thread::sleep(wait.min(POLL_INTERVAL));
+        continue;
+      }
+
+      // Recorded before checking so a failing check waits a full interval too.
+      let mut state = read_update_check_state(&root);
+      state.last_checked_at = unix_now();
+      if let Err(error) = write_update_check_state(&root, &state) {
+        warn!("[update] save check time failed: {error:#}");
+      }
+      info!("[update] scheduled check");
+      if settings.pinned_line_version.is_none() {
+        check_line_update(&app);
+      }
+      check_store_updates(&app);
+    }
+  });
+}
+
+/// Time left until the next check, or `None` when periodic checks are off.
+fn next_check_delay(extensions_root: &Path, interval_hours: u64) -> Option<Duration> {
+  if interval_hours == 0 {
+    return None;
+  }
+  let due_at = read_update_check_state(extensions_root)
+    .last_checked_at
+    .saturating_add(interval_hours.saturating_mul(60 * 60));
+  Some(Duration::from_secs(due_at.saturating_sub(unix_now())))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn etag_is_dropped_when_the_installed_version_changes() {
+    let root = tempfile::tempdir().unwrap();
+    let key = "ext update2 https://example.invalid/service/update2/crx";
+    remember_etag(root.path(), key, Some("3.1.0"), Some("\"abc\""));
+    assert_eq!(
+      cached_etag(root.path(), key, Some("3.1.0")).as_deref(),
+      Some("\"abc\"")
+    );
+    assert_eq!(cached_etag(root.path(), key, Some("3.0.0")), None);
+    assert_eq!(cached_etag(root.path(), key, None), None);
+
+    remember_etag(root.path(), key, Some("3.0.0"), Some("\"def\""));
+    assert_eq!(cached_etag(root.path(), key, Some("3.1.0")), None);
+    remember_etag(root.path(), key, Some("3.0.0"), None);
+    assert_eq!(cached_etag(root.path(), key, Some("3.0.0")), None);
+  }
+}
@@ -0,0 +1,300 @@
+use crate::crx::{discard_partial_download, download_crx, verify_crx_payload, AcceptedCrx};
+use crate::http_client::http_agent;
+use crate::progress::{ExtensionProgress, ProgressReporter};
+use crate::update2::{
+  build_update_url, check_update, compare_versions, UpdateManifest, UpdateStatus,
+};
+use crate::update_schedule::{cached_etag, etag_key, remember_etag};
+use anyhow::{anyhow, Result};
+use log::info;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use url::Url;
+
+const MIRROR_INDEX_FILE: &str = "index.json";
+
+/// Where the LINE extension can be fetched from, tried in config order.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub(crate) enum UpdateSource {
+  /// An Omaha update2 endpoint such as clients2.google.com.
+  Update2 { url: String },
+  /// An `http(s)://` or `file://` directory holding CRX files and an `index.json`.
+  Mirror { url: String },
+}
+
+impl fmt::Display for UpdateSource {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      UpdateSource::Update2 { url } => write!(f, "update2 {url}"),
+      UpdateSource::Mirror { url } => write!(f, "mirror {url}"),
+    }
+  }
+}
+
+impl UpdateSource {
+  pub(crate) fn accepted_crx(&self) -> AcceptedCrx {
+    match self {
+      UpdateSource::Update2 { .. } => AcceptedCrx::Crx3,
+      UpdateSource::Mirror { .. } => AcceptedCrx::Crx2OrCrx3,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct MirrorIndex {
+  extensions: Vec<MirrorEntry>,
+}
+
+#[derive(Deserialize)]
+struct MirrorEntry {
+  id: String,
+  version: String,
+  file: String,
+  sha256: Option<String>,
+  size: Option<u64>,
+}
+
+/// `etag` is the `ETag` stored by the last check that found nothing new;
+/// an unchanged response comes back as `NoUpdate` without a body.
+pub(crate) fn check_source(
+  source: &UpdateSource,
+  extension_id: &str,
+  current_version: Option<&str>,
+  etag: Option<&str>,
+) -> Result<UpdateManifest> {
+  match source {
+    UpdateSource::Update2 { url } => {
+      let update_url = build_update_url(url, extension_id, current_version);
+      check_update(&update_url, extension_id, etag)
+    }
+    UpdateSource::Mirror { url } => check_mirror(url, extension_id, etag),
+  }
+}
+
+fn check_mirror(url: &str, extension_id: &str, etag: Option<&str>) -> Result<UpdateManifest> {
+  let base = mirror_base(url)?;
+  let index_url = base.join(MIRROR_INDEX_FILE)?;
+  let (bytes, index_etag) = if index_url.scheme() == "file" {
+    (fetch_bytes(&index_url)?, None)
+  } else {
+    let mut request = http_agent().get(index_url.as_str());
+    if let Some(etag) = etag {
+      request = request.set("If-None-Match", etag);
+    }
+    let response = request
+      .call()
+      .map_err(|error| anyhow!("fetch {index_url} failed: {error}"))?;
+    if let (304, Some(etag)) = (response.status(), etag) {
+      return Ok(UpdateManifest::not_modified(etag));
+    }
+    let index_etag = response.header("ETag").map(str::to_string);
+    let mut buffer = Vec::new();
+    response.into_reader().read_to_end(&mut buffer)?;
+    (buffer, index_etag)
+  };
+  let raw = String::from_utf8(bytes)?;
+  let index: MirrorIndex = serde_json::from_str(&raw)?;
+  let entry = index
+    .extensions
+    .into_iter()
+    .filter(|entry| entry.id == extension_id)
+    .max_by(|a, b| compare_versions(&a.version, &b.version))
+    .ok_or_else(|| anyhow!("mirror has no entry for {extension_id}"))?;
+
+  let codebase = mirror_file_url(&base, &entry.file)?;
+
+  Ok(UpdateManifest {
+    status: UpdateStatus::Ok,
+    codebase: Some(codebase.to_string()),
+    version: Some(entry.version),
+    hash_sha256: entry.sha256.map(|hash| hash.to_ascii_lowercase()),
+    size: entry.size,
+    etag: index_etag,
+  })
+}
+
+fn mirror_base(url: &str) -> Result<Url> {
+  let mut base = Url::parse(url)?;
+  if !base.path().ends_with('/') {
+    let path = format!("{}/", base.path());
+    base.set_path(&path);
+  }
+  Ok(base)
+}
+
+/// Resolves an `index.json` file name, which must stay inside the mirror.
+fn mirror_file_url(base: &Url, file: &str) -> Result<Url> {
+  let url = base.join(file)?;
+  let same_origin = url.scheme() == base.scheme()
+    && url.host_str() == base.host_str()
+    && url.port_or_known_default() == base.port_or_known_default();
+  if !same_origin || !url.path().starts_with(base.path()) {
+    return Err(anyhow!("mirror file {file} is outside {base}"));
+  }
+  Ok(url)
+}
+
+/// A CRX newer than the installed version, checked against the update
+/// response it was offered in.
+pub(crate) struct DownloadedUpdate {
+  pub(crate) version: String,
+  pub(crate) crx_bytes: Vec<u8>,
+  pub(crate) crx_sha256: String,
+}
+
+/// Asks `source` for a version of `extension_id` newer than `current_version`
+/// and downloads it into `part_path`, resuming what an earlier attempt left
+/// there. Returns `None` when nothing newer is offered or `skip_version`
+/// turns the offered version down.
+pub(crate) fn download_update(
+  source: &UpdateSource,
+  extension_id: &str,
+  current_version: Option<&str>,
+  extensions_root: &Path,
+  part_path: &Path,
+  progress: &ProgressReporter,
+  skip_version: impl Fn(&str) -> bool,
+) -> Result<Option<DownloadedUpdate>> {
+  progress.emit(ExtensionProgress::Checking {
+    source: source.to_string(),
+  });
+  info!(
+    "[update] check {extension_id} v{} {source}",
+    current_version.unwrap_or("-")
+  );
+  let etag_key = etag_key(extension_id, source);
+  let etag = cached_etag(extensions_root, &etag_key, current_version);
+  let manifest = check_source(source, extension_id, current_version, etag.as_deref())?;
+
+  let version = match manifest.status {
+    UpdateStatus::Ok => manifest
+      .version
+      .as_deref()
+      .ok_or_else(|| anyhow!("updatecheck without version"))?,
+    UpdateStatus::NoUpdate if current_version.is_some() => {
+      info!("[update] no update");
+      remember_etag(
+        extensions_root,
+        &etag_key,
+        current_version,
+        manifest.etag.as_deref(),
+      );
+      return Ok(None);
+    }
+    UpdateStatus::NoUpdate => {
+      return Err(anyhow!("server reported noupdate without local extension"));
+    }
+    UpdateStatus::Error(status) => {
+      return Err(anyhow!("check failed: status={status}"));
+    }
+  };
+  let codebase = manifest
+    .codebase
+    .as_deref()
+    .ok_or_else(|| anyhow!("updatecheck without codebase"))?;
+
+  if let Some(current) = current_version {
+    if compare_versions(version, current) != Ordering::Greater {
+      info!("[update] remote v{version} is not newer");
+      remember_etag(
+        extensions_root,
+        &etag_key,
+        current_version,
+        manifest.etag.as_deref(),
+      );
+      return Ok(None);
+    }
+  }
+  remember_etag(extensions_root, &etag_key, current_version, None);
+  if skip_version(version) {
+    return Ok(None);
+  }
+
+  info!("[update] update available v{version}");
+  info!("[update] download {codebase}");
+  fetch_crx(codebase, part_path, &mut |received, total| {
+    progress.emit(ExtensionProgress::Downloading {
+      received,
+      total: total.or(manifest.size),
+    });
+  })?;
+  progress.emit(ExtensionProgress::Verifying);
+  // Only a finished download is discarded; a failed one is resumed next time.
+  let crx_bytes = fs::read(part_path);
+  discard_partial_download(part_path);
+  let crx_bytes = crx_bytes?;
+  let crx_sha256 = verify_crx_payload(&crx_bytes, manifest.hash_sha256.as_deref(), manifest.size)?;
+  Ok(Some(DownloadedUpdate {
+    version: version.to_string(),
+    crx_bytes,
+    crx_sha256,
+  }))
+}
+
+/// Fetches a CRX from an `http(s)://` or `file://` URL into `part_path`.
+pub(crate) fn fetch_crx(
+  url: &str,
+  part_path: &Path,
+  on_progress: &mut dyn FnMut(u64, Option<u64>),
+) -> Result<()> {
+  let parsed = Url::parse(url)?;
+  if parsed.scheme() == "file" {
+    let bytes = fetch_bytes(&parsed)?;
+    fs::write(part_path, &bytes)?;
+    on_progress(bytes.len() as u64, Some(bytes.len() as u64));
+    return Ok(());
+  }
+  download_crx(url, part_path, on_progress)
+}
+
+fn fetch_bytes(url: &Url) -> Result<Vec<u8>> {
+  if url.scheme() == "file" {
+    let path = url
+      .to_file_path()
+      .map_err(|_| anyhow!("invalid file url: {url}"))?;
+    return fs::read(&path).map_err(|error| anyhow!("read {} failed: {error}", path.display()));
+  }
+
+  let response = http_agent()
+    .get(url.as_str())
+    .call()
+    .map_err(|error| anyhow!("fetch {url} failed: {error}"))?;
+  let mut buffer = Vec::new();
+  response.into_reader().read_to_end(&mut buffer)?;
+  Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn mirror_files_stay_inside_the_mirror() {
+    let base = mirror_base("https://mirror.example.com/line").unwrap();
+    assert_eq!(
+      mirror_file_url(&base, "crx/line-3.7.1.crx")
+        .unwrap()
+        .as_str(),
+      "https://mirror.example.com/line/crx/line-3.7.1.crx"
+    );
+    for file in [
+      "../line.crx",
+      "crx/../../line.crx",
+      "/line.crx",
+      "https://other.example.com/line/line.crx",
+      "http://mirror.example.com/line/line.crx",
+      "file:///C:/line/line.crx",
+    ] {
+      assert!(mirror_file_url(&base, file).is_err(), "{file}");
+    }
+
+    let base = mirror_base("file:///D:/line-mirror/").unwrap();
+    assert!(mirror_file_url(&base, "line.crx").is_ok());
+    assert!(mirror_file_url(&base, "../secret.crx").is_err());
+  }
+}
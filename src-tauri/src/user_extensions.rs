@@ -0,0 +1,619 @@
+use crate::config::{load_config, AppConfig};
+use crate::crx::{
+  ensure_clean_dir, extension_id_from_public_key, extract_zip, inject_manifest_key, parse_crx,
+  unpack_crx, verify_manifest_key, AcceptedCrx, ExtractLimits,
+};
+use crate::extensions::{
+  is_extension_dir, read_manifest_version, retry_with_wait, unix_now, InstallRecord,
+};
+use crate::manifest::ExtensionManifest;
+use crate::paths::extensions_root;
+use crate::progress::ProgressReporter;
+use crate::update2::compare_versions;
+use crate::update_sources::{download_update, UpdateSource};
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tauri_plugin_dialog::{DialogExt, MessageDialogButtons};
+
+const USER_EXTENSIONS_DIR: &str = "user";
+/// Folders that could not be deleted while WebView2 had them loaded.
+const PENDING_REMOVALS_FILE: &str = "user.remove.json";
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UserExtension {
+  /// Folder name under `extensions/user`, as stored in `disabledUserExtensions`.
+  pub(crate) id: String,
+  pub(crate) name: String,
+  pub(crate) version: Option<String>,
+  pub(crate) enabled: bool,
+  /// Why the manifest was rejected; such folders are not loaded.
+  pub(crate) error: Option<String>,
+  /// Update source for extensions installed from the Web Store by ID.
+  pub(crate) source: Option<String>,
+}
+
+pub(crate) fn user_extensions_dir(extensions_root: &Path) -> PathBuf {
+  extensions_root.join(USER_EXTENSIONS_DIR)
+}
+
+/// Every folder under `extensions/user` except ones queued for removal.
+pub(crate) fn list_user_extensions(
+  extensions_root: &Path,
+  disabled: &[String],
+) -> Vec<UserExtension> {
+  let pending_removals = read_pending_removals(extensions_root);
+  let user_dir = user_extensions_dir(extensions_root);
+  extension_folders(&user_dir)
+    .into_iter()
+    .filter(|(id, _)| !pending_removals.contains(id))
+    .map(|(id, path)| {
+      let enabled = !disabled.contains(&id);
+      let source = read_store_record(&user_dir, &id).and_then(|record| record.source);
+      match load_valid_manifest(&path) {
+        Ok(manifest) => UserExtension {
+          name: manifest.display_name(&path),
+          version: Some(manifest.version),
+          id,
+          enabled,
+          error: None,
+          source,
+        },
+        Err(error) => UserExtension {
+          name: id.clone(),
+          version: None,
+          id,
+          enabled,
+          error: Some(format!("{error:#}")),
+          source,
+        },
+      }
+    })
+    .collect()
+}
+
+/// Directories of the enabled, valid user extensions. `user_dir` itself may
+/// also be a single unpacked extension, as in older installs.
+pub(crate) fn collect_user_extension_dirs(
+  user_dir: &Path,
+  disabled: &[String],
+) -> Result<Vec<PathBuf>> {
+  let candidates = if is_extension_dir(user_dir) {
+    vec![user_dir.to_path_buf()]
+  } else {
+    extension_folders(user_dir)
+      .into_iter()
+      .filter(|(id, _)| {
+        let enabled = !disabled.contains(id);
+        if !enabled {
+          info!("[ext] user extension {id} disabled");
+        }
+        enabled
+      })
+      .map(|(_, path)| path)
+      .collect()
+  };
+
+  let mut dirs = Vec::new();
+  for path in candidates {
+    match load_valid_manifest(&path) {
+      Ok(manifest) => {
+        info!(
+          "[ext] user extension {} v{} ({})",
+          manifest.display_name(&path),
+          manifest.version,
+          path.display()
+        );
+        dirs.push(path);
+      }
+      Err(error) => warn!("[ext] skip user extension {}: {error:#}", path.display()),
+    }
+  }
+  Ok(dirs)
+}
+
+/// Copies an unpacked extension folder, or unpacks a `.crx`, into `user_dir`.
+pub(crate) fn add_user_extension(
+  user_dir: &Path,
+  source: &Path,
+  limits: &ExtractLimits,
+) -> Result<UserExtension> {
+  fs::create_dir_all(user_dir)?;
+  let is_crx = source.extension().and_then(|ext| ext.to_str()) == Some("crx");
+  let id = if is_crx {
+    add_from_crx(user_dir, source, limits)?
+  } else {
+    add_from_folder(user_dir, source)?
+  };
+  info!("[ext] added user extension {id} from {}", source.display());
+  describe_user_extension(user_dir, id)
+}
+
+fn describe_user_extension(user_dir: &Path, id: String) -> Result<UserExtension> {
+  let dir = user_dir.join(&id);
+  let manifest = ExtensionManifest::load(&dir)?;
+  Ok(UserExtension {
+    name: manifest.display_name(&dir),
+    version: Some(manifest.version),
+    source: read_store_record(user_dir, &id).and_then(|record| record.source),
+    id,
+    enabled: true,
+    error: None,
+  })
+}
+
+fn add_from_folder(user_dir: &Path, source: &Path) -> Result<String> {
+  if !is_extension_dir(source) {
+    return Err(anyhow!("{} has no manifest.json", source.display()));
+  }
+  load_valid_manifest(source)?;
+  let base = source
+    .file_name()
+    .and_then(|name| name.to_str())
+    .map(|name| name.trim_start_matches('.').to_string())
+    .filter(|name| !name.is_empty())
+    .unwrap_or_else(|| "extension".to_string());
+  let mut id = base.clone();
+  let mut suffix = 2;
+  while user_dir.join(&id).exists() {
+    id = format!("{base}-{suffix}");
+    suffix += 1;
+  }
+
+  let staging_dir = user_dir.join(format!(".{id}.staging"));
+  ensure_clean_dir(&staging_dir)?;
+  let result = copy_dir_all(source, &staging_dir)
+    .and_then(|_| Ok(fs::rename(&staging_dir, user_dir.join(&id))?));
+  if result.is_err() {
+    let _ = fs::remove_dir_all(&staging_dir);
+  }
+  result.map(|_| id)
+}
+
+fn add_from_crx(user_dir: &Path, source: &Path, limits: &ExtractLimits) -> Result<String> {
+  let bytes =
+    fs::read(source).map_err(|error| anyhow!("read {} failed: {error}", source.display()))?;
+  let parsed = parse_crx(&bytes, AcceptedCrx::Crx2OrCrx3)?;
+  let id = extension_id_from_public_key(&parsed.public_key);
+  if user_dir.join(&id).exists() {
+    return Err(anyhow!("extension {id} is already installed"));
+  }
+
+  let staging_dir = user_dir.join(format!(".{id}.staging"));
+  ensure_clean_dir(&staging_dir)?;
+  let result = extract_zip(&parsed.zip_bytes, &staging_dir, limits)
+    .map_err(Into::into)
+    .and_then(|_| inject_manifest_key(&staging_dir, &parsed.public_key))
+    .and_then(|_| load_valid_manifest(&staging_dir).map(|_| ()))
+    .and_then(|_| Ok(fs::rename(&staging_dir, user_dir.join(&id))?));
+  if result.is_err() {
+    let _ = fs::remove_dir_all(&staging_dir);
+  }
+  result.map(|_| id)
+}
+
+/// Deletes a user extension folder. If WebView2 still holds it open, the
+/// folder is queued and removed by `apply_pending_user_removals` next launch.
+pub(crate) fn remove_user_extension(extensions_root: &Path, id: &str) -> Result<()> {
+  let user_dir = user_extensions_dir(extensions_root);
+  let dir = user_extension_path(&user_dir, id)?;
+  if !dir.exists() {
+    return Err(anyhow!("user extension {id} not found"));
+  }
+  discard_store_update(&user_dir, id);
+  let _ = fs::remove_file(store_record_path(&user_dir, id));
+  match fs::remove_dir_all(&dir) {
+    Ok(()) => {
+      info!("[ext] removed user extension {id}");
+      Ok(())
+    }
+    Err(error) => {
+      info!("[ext] remove {id} deferred: {error}");
+      let mut pending = read_pending_removals(extensions_root);
+      if !pending.iter().any(|entry| entry == id) {
+        pending.push(id.to_string());
+      }
+      write_pending_removals(extensions_root, &pending)
+    }
+  }
+}
+
+/// Finishes removals queued by `remove_user_extension` before WebView2 starts.
+pub(crate) fn apply_pending_user_removals(extensions_root: &Path) {
+  let pending = read_pending_removals(extensions_root);
+  if pending.is_empty() {
+    return;
+  }
+  let user_dir = user_extensions_dir(extensions_root);
+  let mut remaining = Vec::new();
+  for id in pending {
+    let Ok(dir) = user_extension_path(&user_dir, &id) else {
+      continue;
+    };
+    if !dir.exists() {
+      continue;
+    }
+    match fs::remove_dir_all(&dir) {
+      Ok(()) => info!("[ext] removed user extension {id}"),
+      Err(error) => {
+        warn!("[ext] remove {id} failed: {error}");
+        remaining.push(id);
+      }
+    }
+  }
+  if let Err(error) = write_pending_removals(extensions_root, &remaining) {
+    warn!("[ext] pending removals write failed: {error:#}");
+  }
+}
+
+pub(crate) fn user_extension_exists(user_dir: &Path, id: &str) -> bool {
+  user_extension_path(user_dir, id)
+    .map(|path| is_extension_dir(&path))
+    .unwrap_or(false)
+}
+
+fn user_extension_path(user_dir: &Path, id: &str) -> Result<PathBuf> {
+  let is_plain_name = !id.is_empty()
+    && !id.starts_with('.')
+    && !id.contains(['/', '\\'])
+    && Path::new(id).file_name().and_then(|name| name.to_str()) == Some(id);
+  if !is_plain_name {
+    return Err(anyhow!("invalid user extension id {id}"));
+  }
+  Ok(user_dir.join(id))
+}
+
+/// `(id, path)` of every extension folder, skipping hidden staging folders.
+fn extension_folders(user_dir: &Path) -> Vec<(String, PathBuf)> {
+  let Ok(entries) = fs::read_dir(user_dir) else {
+    return Vec::new();
+  };
+  let mut folders: Vec<(String, PathBuf)> = entries
+    .filter_map(|entry| entry.ok())
+    .map(|entry| entry.path())
+    .filter(|path| path.is_dir() && is_extension_dir(path))
+    .filter_map(|path| {
+      let id = path.file_name()?.to_str()?.to_string();
+      (!id.starts_with('.')).then_some((id, path))
+    })
+    .collect();
+  folders.sort();
+  folders
+}
+
+fn load_valid_manifest(dir: &Path) -> Result<ExtensionManifest> {
+  let manifest = ExtensionManifest::load(dir)?;
+  manifest.validate()?;
+  Ok(manifest)
+}
+
+fn copy_dir_all(source: &Path, dest: &Path) -> Result<()> {
+  fs::create_dir_all(dest)?;
+  for entry in fs::read_dir(source)? {
+    let entry = entry?;
+    let file_type = entry.file_type()?;
+    let target = dest.join(entry.file_name());
+    if file_type.is_dir() {
+      copy_dir_all(&entry.path(), &target)?;
+    } else if file_type.is_file() {
+      fs::copy(entry.path(), &target)?;
+    } else {
+      // A partial copy would load as a broken extension.
+      return Err(anyhow!(
+        "{} is a symlink or special file",
+        entry.path().display()
+      ));
+    }
+  }
+  Ok(())
+}
+
+fn read_pending_removals(extensions_root: &Path) -> Vec<String> {
+  fs::read_to_string(extensions_root.join(PENDING_REMOVALS_FILE))
+    .ok()
+    .and_then(|raw| serde_json::from_str(&raw).ok())
+    .unwrap_or_default()
+}
+
+fn write_pending_removals(extensions_root: &Path, pending: &[String]) -> Result<()> {
+  let path = extensions_root.join(PENDING_REMOVALS_FILE);
+  if pending.is_empty() {
+    if path.exists() {
+      fs::remove_file(path)?;
+    }
+    return Ok(());
+  }
+  fs::write(path, serde_json::to_string_pretty(pending)?)?;
+  Ok(())
+}
+
+fn store_record_path(user_dir: &Path, id: &str) -> PathBuf {
+  user_dir.join(format!("{id}.install.json"))
+}
+
+fn store_ready_dir(user_dir: &Path, id: &str) -> PathBuf {
+  user_dir.join(format!(".{id}.ready"))
+}
+
+fn store_ready_record_path(user_dir: &Path, id: &str) -> PathBuf {
+  user_dir.join(format!(".{id}.ready.json"))
+}
+
+fn read_store_record(user_dir: &Path, id: &str) -> Option<InstallRecord> {
+  let raw = fs::read_to_string(store_record_path(user_dir, id)).ok()?;
+  serde_json::from_str(&raw).ok()
+}
+
+fn write_record(path: &Path, record: &InstallRecord) -> Result<()> {
+  fs::write(path, serde_json::to_string_pretty(record)?)?;
+  Ok(())
+}
+
+/// Accepts a bare extension ID or a Web Store URL ending in one.
+pub(crate) fn parse_store_extension_id(input: &str) -> Result<String> {
+  let is_extension_id =
+    |value: &str| value.len() == 32 && value.bytes().all(|byte| (b'a'..=b'p').contains(&byte));
+  input
+    .trim()
+    .split(['/', '?', '#'])
+    .map(str::trim)
+    .find(|segment| is_extension_id(segment))
+    .map(str::to_string)
+    .ok_or_else(|| anyhow!("no extension id in {input:?}"))
+}
+
+/// Downloads a Web Store extension into `extensions/user/<id>` through the
+/// same update sources and checks as the LINE extension. The sources are
+/// tried once, since the settings window reports the error; trying again
+/// resumes the download.
+pub(crate) fn install_store_extension(
+  config: &AppConfig,
+  extensions_root: &Path,
+  id: &str,
+  progress: &ProgressReporter,
+) -> Result<UserExtension> {
+  let user_dir = &user_extensions_dir(extensions_root);
+  fs::create_dir_all(user_dir)?;
+  let dir = user_extension_path(user_dir, id)?;
+  if dir.exists() {
+    return Err(anyhow!("extension {id} is already installed"));
+  }
+  let (staging_dir, record) = fetch_store_extension(config, extensions_root, id, None, progress)?
+    .ok_or_else(|| anyhow!("{id} has no release"))?;
+  if let Err(error) = fs::rename(&staging_dir, &dir) {
+    let _ = fs::remove_dir_all(&staging_dir);
+    return Err(error.into());
+  }
+  write_record(&store_record_path(user_dir, id), &record)?;
+  info!(
+    "[ext] installed store extension {id} v{}",
+    record.version.as_deref().unwrap_or("-")
+  );
+  describe_user_extension(user_dir, id.to_string())
+}
+
+/// Checks every extension installed by ID and stages newer versions as
+/// `.<id>.ready`, applied by `apply_store_updates` on the next launch.
+/// Returns the display names of the staged updates.
+pub(crate) fn stage_store_updates(
+  config: &AppConfig,
+  extensions_root: &Path,
+  progress: &ProgressReporter,
+) -> Vec<String> {
+  let user_dir = user_extensions_dir(extensions_root);
+  let mut staged = Vec::new();
+  for (id, dir) in extension_folders(&user_dir) {
+    if read_store_record(&user_dir, &id).is_none() {
+      continue;
+    }
+    let ready_dir = store_ready_dir(&user_dir, &id);
+    let current_version = match (
+      read_manifest_version(&dir),
+      read_manifest_version(&ready_dir),
+    ) {
+      (Some(current), Some(ready)) if compare_versions(&ready, &current) == Ordering::Greater => {
+        Some(ready)
+      }
+      (current, _) => current,
+    };
+    let fetched = retry_with_wait(&format!("{id} update check"), progress, true, || {
+      fetch_store_extension(
+        config,
+        extensions_root,
+        &id,
+        current_version.as_deref(),
+        progress,
+      )
+    });
+    match fetched {
+      Ok(Some((staging_dir, record))) => {
+        discard_store_update(&user_dir, &id);
+        let result = fs::rename(&staging_dir, &ready_dir)
+          .map_err(Into::into)
+          .and_then(|_| write_record(&store_ready_record_path(&user_dir, &id), &record));
+        match result {
+          Ok(()) => {
+            let version = record.version.unwrap_or_default();
+            info!("[ext] staged store extension {id} v{version}");
+            let name = ExtensionManifest::load(&ready_dir)
+              .map(|manifest| manifest.display_name(&ready_dir))
+              .unwrap_or_else(|_| id.clone());
+            staged.push(format!("{name} v{version}"));
+          }
+          Err(error) => {
+            warn!("[ext] stage {id} failed: {error:#}");
+            let _ = fs::remove_dir_all(&staging_dir);
+            discard_store_update(&user_dir, &id);
+          }
+        }
+      }
+      Ok(None) => {}
+      Err(error) => warn!("[ext] update {id} failed: {error:#}"),
+    }
+  }
+  staged
+}
+
+/// Swaps staged Web Store updates into place before WebView2 loads them.
+pub(crate) fn apply_store_updates(extensions_root: &Path) {
+  let user_dir = user_extensions_dir(extensions_root);
+  for (id, dir) in extension_folders(&user_dir) {
+    let ready_dir = store_ready_dir(&user_dir, &id);
+    if !is_extension_dir(&ready_dir) {
+      continue;
+    }
+    let previous_dir = user_dir.join(format!(".{id}.previous"));
+    let result = (|| -> Result<()> {
+      if previous_dir.exists() {
+        fs::remove_dir_all(&previous_dir)?;
+      }
+      fs::rename(&dir, &previous_dir)?;
+      if let Err(error) = fs::rename(&ready_dir, &dir) {
+        fs::rename(&previous_dir, &dir)?;
+        return Err(error.into());
+      }
+      Ok(())
+    })();
+    match result {
+      Ok(()) => {
+        let _ = fs::remove_dir_all(&previous_dir);
+        let _ = fs::rename(
+          store_ready_record_path(&user_dir, &id),
+          store_record_path(&user_dir, &id),
+        );
+        info!(
+          "[ext] applied store extension {id} v{}",
+          read_manifest_version(&dir).as_deref().unwrap_or("-")
+        );
+      }
+      Err(error) => {
+        warn!("[ext] apply {id} update failed: {error:#}");
+        discard_store_update(&user_dir, &id);
+      }
+    }
+  }
+}
+
+fn discard_store_update(user_dir: &Path, id: &str) {
+  let ready_dir = store_ready_dir(user_dir, id);
+  if ready_dir.exists() {
+    let _ = fs::remove_dir_all(&ready_dir);
+  }
+  let _ = fs::remove_file(store_ready_record_path(user_dir, id));
+}
+
+/// Tries the configured update sources in order. Returns the verified,
+/// unpacked extension in a staging folder, or `None` when `current_version`
+/// is already the newest.
+fn fetch_store_extension(
+  config: &AppConfig,
+  extensions_root: &Path,
+  id: &str,
+  current_version: Option<&str>,
+  progress: &ProgressReporter,
+) -> Result<Option<(PathBuf, InstallRecord)>> {
+  let mut last_error = None;
+  for source in config.update_sources() {
+    match fetch_from_source(
+      &source,
+      &config.extract_limits,
+      extensions_root,
+      id,
+      current_version,
+      progress,
+    ) {
+      Ok(result) => return Ok(result),
+      Err(error) => {
+        warn!("[ext] {id} {source} failed: {error:#}");
+        last_error = Some(error);
+      }
+    }
+  }
+  Err(last_error.unwrap_or_else(|| anyhow!("no update sources configured")))
+}
+
+fn fetch_from_source(
+  source: &UpdateSource,
+  limits: &ExtractLimits,
+  extensions_root: &Path,
+  id: &str,
+  current_version: Option<&str>,
+  progress: &ProgressReporter,
+) -> Result<Option<(PathBuf, InstallRecord)>> {
+  let user_dir = &user_extensions_dir(extensions_root);
+  let Some(update) = download_update(
+    source,
+    id,
+    current_version,
+    extensions_root,
+    &user_dir.join(format!(".{id}.crx.part")),
+    progress,
+    |_| false,
+  )?
+  else {
+    return Ok(None);
+  };
+
+  let staging_dir = user_dir.join(format!(".{id}.staging"));
+  unpack_crx(
+    &update.crx_bytes,
+    source.accepted_crx(),
+    id,
+    &staging_dir,
+    limits,
+  )?;
+  let staged =
+    verify_manifest_key(&staging_dir, id).and_then(|_| load_valid_manifest(&staging_dir));
+  let staged_manifest = match staged {
+    Ok(staged_manifest) => staged_manifest,
+    Err(error) => {
+      let _ = fs::remove_dir_all(&staging_dir);
+      return Err(error);
+    }
+  };
+
+  let record = InstallRecord {
+    version: Some(staged_manifest.version),
+    crx_sha256: Some(update.crx_sha256),
+    installed_at: unix_now(),
+    source: Some(source.to_string()),
+    ..InstallRecord::default()
+  };
+  Ok(Some((staging_dir, record)))
+}
+
+/// Looks for Web Store extension updates and offers a restart once any are
+/// staged.
+pub(crate) fn check_store_updates(app: &tauri::AppHandle) {
+  let staged = match (load_config(app), extensions_root(app)) {
+    (Ok(config), Ok(root)) => stage_store_updates(&config, &root, &ProgressReporter::new(app)),
+    (Err(error), _) | (_, Err(error)) => {
+      warn!("[ext] store update check failed: {error:#}");
+      return;
+    }
+  };
+  if staged.is_empty() {
+    return;
+  }
+  let dialog_handle = app.clone();
+  let _ = app.run_on_main_thread(move || {
+    let app_handle = dialog_handle.clone();
+    dialog_handle
+      .dialog()
+      .message(format!(
+        "拡張機能を更新しました。再起動しますか？\n\n{}",
+        staged.join("\n")
+      ))
+      .title("更新完了")
+      .buttons(MessageDialogButtons::YesNo)
+      .show(move |confirmed| {
+        if confirmed {
+          app_handle.restart();
+        }
+      });
+  });
+}